@@ -0,0 +1,516 @@
+//! In-process cache for query results and rendered responses.
+//!
+//! The data behind every page only changes when the collectors run, so
+//! entries stay valid until a cheap probe query reports a different database
+//! state. Entries are additionally bounded by a TTL and a total size limit.
+//!
+//! Concurrent misses of the same key are computed once, the other requests
+//! wait for the first one and are served what it stored.
+
+use crate::config;
+use crate::db::Db;
+use crate::utils::{Ext, Result};
+use axum::body::{boxed, Bytes, Full};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use indexmap::IndexMap;
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Values stored in the cache report their approximate size in bytes,
+/// including the heap data they own.
+pub trait Weight {
+    fn weight(&self) -> usize;
+}
+
+impl Weight for String {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>() + self.capacity()
+    }
+}
+
+impl<K: Weight, V: Weight> Weight for IndexMap<K, V> {
+    fn weight(&self) -> usize {
+        // every entry also has a hash and a slot in the index table
+        let overhead = 2 * std::mem::size_of::<usize>();
+        let spare = (self.capacity() - self.len()) * (std::mem::size_of::<(K, V)>() + overhead);
+        let entries: usize = self.iter().map(|(k, v)| k.weight() + v.weight() + overhead).sum();
+        std::mem::size_of::<Self>() + spare + entries
+    }
+}
+
+impl<T: Weight> Weight for Vec<T> {
+    fn weight(&self) -> usize {
+        let spare = (self.capacity() - self.len()) * std::mem::size_of::<T>();
+        std::mem::size_of::<Self>() + spare + self.iter().map(Weight::weight).sum::<usize>()
+    }
+}
+
+impl Weight for usize {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+impl Weight for time::OffsetDateTime {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+struct Entry {
+    value: Arc<dyn Any + Send + Sync>,
+    weight: usize,
+    inserted: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    stamp: Option<String>,
+    probed: Option<Instant>,
+    entries: IndexMap<String, Entry>,
+    size: usize,
+}
+
+impl State {
+    /// Drop the oldest entries until `weight` more bytes fit in `max_size`.
+    fn evict(&mut self, weight: usize, max_size: usize) {
+        while self.size + weight > max_size {
+            match self.entries.shift_remove_index(0) {
+                Some((_, entry)) => self.size -= entry.weight,
+                None => break,
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /// Record the result of a probe, dropping every entry if it changed.
+    fn update_stamp(&mut self, stamp: Option<String>) {
+        if self.stamp != stamp {
+            debug!("database changed ({:?} -> {:?}), clearing cache", self.stamp, stamp);
+            self.clear();
+            self.stamp = stamp;
+        }
+        self.probed = Some(Instant::now());
+    }
+}
+
+pub struct Cache {
+    config: RwLock<Arc<config::Cache>>,
+    state: Mutex<State>,
+    /// held while the databases are probed
+    probing: tokio::sync::Mutex<()>,
+    /// keys whose value is being computed, see [`Cache::lock_key`]
+    pending: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Held while the value of a key is computed.
+struct KeyLock<'a> {
+    cache: &'a Cache,
+    key: String,
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl Drop for KeyLock<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.cache.pending();
+        // one reference in the map and one in the guard, so nobody is waiting
+        if pending.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) <= 2) {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(config: &config::Cache) -> Self {
+        Self {
+            config: RwLock::new(Arc::new(config.clone())),
+            state: Mutex::new(State::default()),
+            probing: tokio::sync::Mutex::new(()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config().enabled
+    }
+
+    fn config(&self) -> Arc<config::Cache> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<tokio::sync::Mutex<()>>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until no other request computes `key`. Callers look the key up
+    /// again once they hold the lock.
+    async fn lock_key(&self, key: &str) -> KeyLock<'_> {
+        let lock = self.pending().entry(key.to_string()).or_default().clone();
+
+        KeyLock {
+            cache: self,
+            key: key.to_string(),
+            _guard: lock.lock_owned().await,
+        }
+    }
+
+    /// Apply a reloaded `[cache]` section, dropping what no longer fits.
+    pub fn reconfigure(&self, config: &config::Cache) {
+        let old = self.config();
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config.clone());
+
+        let mut state = self.state();
+        if !config.enabled || config.probe != old.probe || config.pv_probe != old.pv_probe {
+            state.clear();
+            state.stamp = None;
+            state.probed = None;
+        } else {
+            state.evict(0, config.max_size);
+        }
+    }
+
+    /// Probe the databases at most once per `probe_interval` and drop every
+    /// entry when their state has changed.
+    pub async fn refresh(&self, db: &Db) -> Result<()> {
+        let config = self.config();
+        let interval = Duration::from_secs(config.probe_interval);
+        let fresh = || self.state().probed.is_some_and(|probed| probed.elapsed() < interval);
+        if fresh() {
+            return Ok(());
+        }
+
+        // a single probe at a time, the others wait for its result
+        let _probing = self.probing.lock().await;
+        if fresh() {
+            return Ok(());
+        }
+
+        let mut stamp: Option<String> = sqlx::query_scalar(&config.probe)
+            .fetch_one(&mut db.meta.primary().await?)
+            .await?;
+        if let Some(pv_probe) = config.pv_probe.as_deref().filter(|probe| !probe.is_empty()) {
+            let pv_stamp: Option<String> = sqlx::query_scalar(pv_probe)
+                .fetch_one(&mut db.pv.primary().await?)
+                .await?;
+            stamp = Some(format!(
+                "{}|{}",
+                stamp.unwrap_or_default(),
+                pv_stamp.unwrap_or_default()
+            ));
+        }
+
+        self.state().update_stamp(stamp);
+
+        Ok(())
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self, key: &str) -> Option<Arc<T>> {
+        let ttl = Duration::from_secs(self.config().ttl);
+        let mut state = self.state();
+
+        let expired = state.entries.get(key)?.inserted.elapsed() >= ttl;
+        if expired {
+            if let Some(entry) = state.entries.shift_remove(key) {
+                state.size -= entry.weight;
+            }
+            return None;
+        }
+
+        state.entries[key].value.clone().downcast().ok()
+    }
+
+    /// Store `value`, evicting the oldest entries to stay below `max_size`.
+    /// Values larger than the whole cache are not stored.
+    pub fn insert<T: Weight + Send + Sync + 'static>(&self, key: String, value: Arc<T>) {
+        let weight = value.weight();
+        let max_size = self.config().max_size;
+        if weight > max_size {
+            return;
+        }
+
+        let mut state = self.state();
+        if let Some(old) = state.entries.shift_remove(&key) {
+            state.size -= old.weight;
+        }
+        state.evict(weight, max_size);
+
+        state.size += weight;
+        state.entries.insert(
+            key,
+            Entry {
+                value,
+                weight,
+                inserted: Instant::now(),
+            },
+        );
+    }
+
+    /// Return the cached value for `key`, or compute and store it with `f`.
+    pub async fn get_or_try_insert_with<T, F, Fut>(&self, db: &Db, key: &str, f: F) -> Result<Arc<T>>
+    where
+        T: Weight + Send + Sync + 'static,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if !self.enabled() {
+            return Ok(Arc::new(f().await?));
+        }
+
+        self.refresh(db).await?;
+        self.get_or_compute(key, f).await
+    }
+
+    /// [`Cache::get_or_try_insert_with`] once the databases are probed.
+    async fn get_or_compute<T, F, Fut>(&self, key: &str, f: F) -> Result<Arc<T>>
+    where
+        T: Weight + Send + Sync + 'static,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+
+        let _lock = self.lock_key(key).await;
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+
+        let value = Arc::new(f().await?);
+        self.insert(key.to_string(), value.clone());
+
+        Ok(value)
+    }
+}
+
+/// A fully buffered successful response.
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Weight for CachedResponse {
+    fn weight(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.as_str().len() + v.len()).sum();
+        self.body.len() + headers
+    }
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> Response {
+        let mut res = Response::new(boxed(Full::from(self.body.clone())));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+/// The cache key of a response. The same uri is rendered as json for
+/// XMLHttpRequest, and responses are stored compressed, one variant per
+/// `Accept-Encoding` once case and spacing are normalised.
+fn response_key(headers: &HeaderMap, uri: &Uri) -> String {
+    let xhr = headers.get("X-Requested-With").is_some_and(|h| h == "XMLHttpRequest");
+    let encoding: String = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    format!("{}{}:{}", if xhr { "xhr:" } else { "" }, encoding, uri)
+}
+
+/// Middleware serving rendered views from the cache.
+///
+/// Static assets are embedded in the binary and are passed through.
+pub async fn cache_response<B>(db: Ext, req: Request<B>, next: Next<B>) -> Response {
+    let cache = &db.cache;
+    if !cache.enabled() || req.method() != Method::GET || req.uri().path().starts_with("/static/") {
+        return next.run(req).await;
+    }
+
+    if let Err(e) = cache.refresh(&db).await {
+        error!("failed to probe database freshness: {:?}", e);
+        return next.run(req).await;
+    }
+
+    let key = response_key(req.headers(), req.uri());
+
+    if let Some(hit) = cache.get::<CachedResponse>(&key) {
+        return hit.to_response();
+    }
+
+    let _lock = cache.lock_key(&key).await;
+    if let Some(hit) = cache.get::<CachedResponse>(&key) {
+        return hit.to_response();
+    }
+
+    let res = next.run(req).await;
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+
+    let (parts, body) = res.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return crate::utils::Error::Anyhow(anyhow::anyhow!(e.to_string())).into_response(),
+    };
+
+    let cached = Arc::new(CachedResponse {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    });
+    cache.insert(key, cached.clone());
+
+    cached.to_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(max_size: usize) -> Cache {
+        Cache::new(&config::Cache {
+            max_size,
+            ..Default::default()
+        })
+    }
+
+    /// A value weighing `n` bytes.
+    struct Blob(usize);
+
+    impl Weight for Blob {
+        fn weight(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_by_weight() {
+        let cache = cache(100);
+        cache.insert("a".into(), Arc::new(Blob(40)));
+        cache.insert("b".into(), Arc::new(Blob(40)));
+        cache.insert("c".into(), Arc::new(Blob(40)));
+
+        assert!(cache.get::<Blob>("a").is_none());
+        assert!(cache.get::<Blob>("b").is_some());
+        assert!(cache.get::<Blob>("c").is_some());
+        assert_eq!(cache.state().size, 80);
+
+        // replacing a key frees its old weight first
+        cache.insert("c".into(), Arc::new(Blob(60)));
+        assert!(cache.get::<Blob>("b").is_some());
+        assert_eq!(cache.state().size, 100);
+        cache.insert("c".into(), Arc::new(Blob(70)));
+        assert!(cache.get::<Blob>("b").is_none());
+        assert_eq!(cache.state().size, 70);
+
+        // values larger than the whole cache are not stored
+        cache.insert("d".into(), Arc::new(Blob(101)));
+        assert!(cache.get::<Blob>("d").is_none());
+        assert_eq!(cache.state().size, 70);
+    }
+
+    #[test]
+    fn heap_data_counts() {
+        let small = vec![String::from("a")];
+        let large = vec!["a".repeat(1000)];
+        assert!(large.weight() >= small.weight() + 999);
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = cache(100);
+        cache.state().update_stamp(Some("1".into()));
+        cache.insert("a".into(), Arc::new(Blob(10)));
+
+        cache.state().update_stamp(Some("1".into()));
+        assert!(cache.get::<Blob>("a").is_some());
+
+        cache.state().update_stamp(Some("2".into()));
+        assert!(cache.get::<Blob>("a").is_none());
+        assert_eq!(cache.state().size, 0);
+
+        // a new probe query cannot be compared with the old stamp
+        cache.insert("a".into(), Arc::new(Blob(10)));
+        cache.reconfigure(&config::Cache {
+            max_size: 100,
+            probe: "SELECT '1'".into(),
+            ..Default::default()
+        });
+        assert!(cache.get::<Blob>("a").is_none());
+        assert!(cache.state().stamp.is_none());
+
+        // expired entries are dropped on access
+        cache.insert("a".into(), Arc::new(Blob(10)));
+        cache.reconfigure(&config::Cache {
+            max_size: 100,
+            ttl: 0,
+            probe: "SELECT '1'".into(),
+            ..Default::default()
+        });
+        assert!(cache.get::<Blob>("a").is_none());
+        assert_eq!(cache.state().size, 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_compute_once() {
+        let cache = cache(100);
+        let computed = AtomicUsize::new(0);
+
+        let get = || {
+            cache.get_or_compute("a", || async {
+                computed.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Ok(Blob(10))
+            })
+        };
+        let res = futures_util::future::try_join_all((0..8).map(|_| get())).await;
+
+        assert_eq!(res.map(|v| v.len()).ok(), Some(8));
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+        assert!(cache.pending().is_empty());
+    }
+
+    #[test]
+    fn response_keys() {
+        let uri: Uri = "/packages/bash?type=json".parse().unwrap();
+        let key = |headers: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (k, v) in headers {
+                map.insert(*k, v.parse().unwrap());
+            }
+            response_key(&map, &uri)
+        };
+
+        assert_eq!(key(&[]), ":/packages/bash?type=json");
+        assert_eq!(
+            key(&[("accept-encoding", "gzip, BR;q=0.5")]),
+            key(&[("accept-encoding", "gzip,br; q=0.5")])
+        );
+        assert_ne!(key(&[("accept-encoding", "gzip")]), key(&[("accept-encoding", "br")]));
+        assert_eq!(
+            key(&[("x-requested-with", "XMLHttpRequest"), ("accept-encoding", "gzip")]),
+            "xhr:gzip:/packages/bash?type=json"
+        );
+    }
+}
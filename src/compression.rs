@@ -0,0 +1,215 @@
+//! Response compression, see `[compression]` in `config.toml`.
+
+use crate::config;
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+
+/// Negotiated compression for dynamic responses, switched off by [`toggle`]
+/// while `enabled` is false.
+pub fn layer(config: &config::Compression) -> CompressionLayer {
+    let level = config
+        .level
+        .map_or(CompressionLevel::Default, CompressionLevel::Precise);
+
+    CompressionLayer::new().br(true).gzip(true).zstd(true).quality(level)
+}
+
+/// Whether `[compression]` is enabled, shared with the reloader.
+#[derive(Debug, Clone)]
+pub struct Enabled(Arc<AtomicBool>);
+
+impl Enabled {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Middleware hiding `Accept-Encoding` from the cache, the compression layer
+/// and static files while compression is disabled, so that it can be turned
+/// off and on without rebuilding the layers.
+pub async fn toggle<B>(Extension(enabled): Extension<Enabled>, mut req: Request<B>, next: Next<B>) -> Response {
+    if !enabled.get() {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    next.run(req).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Pick the preferred encoding accepted by the client, ties are broken
+    /// in favour of the better compression ratio. `*` stands for encodings
+    /// not listed, and an uncompressed response is only chosen when
+    /// `identity` is strictly preferred.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+
+        let mut listed: [Option<f32>; 3] = [None; 3];
+        let mut any = None;
+        let mut identity = None;
+        for item in accept.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default().to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(1.0, |q| q.parse().unwrap_or(0.0));
+
+            match name.as_str() {
+                "br" => listed[Encoding::Brotli.rank()] = Some(q),
+                "zstd" => listed[Encoding::Zstd.rank()] = Some(q),
+                "gzip" => listed[Encoding::Gzip.rank()] = Some(q),
+                "*" => any = Some(q),
+                "identity" => identity = Some(q),
+                _ => (),
+            }
+        }
+
+        // in rank order, so that a tie keeps the better ratio
+        let mut best: Option<(Self, f32)> = None;
+        for encoding in [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+            let q = listed[encoding.rank()].or(any).unwrap_or(0.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+
+        best.filter(|(_, q)| identity.is_none_or(|identity| *q >= identity))
+            .map(|(encoding, _)| encoding)
+    }
+
+    fn rank(&self) -> usize {
+        match self {
+            Encoding::Brotli => 0,
+            Encoding::Zstd => 1,
+            Encoding::Gzip => 2,
+        }
+    }
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                w.write_all(data)?;
+                Ok(w.into_inner())
+            }
+            Encoding::Zstd => zstd::encode_all(data, 19),
+            Encoding::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                w.write_all(data)?;
+                w.finish()
+            }
+        }
+    }
+}
+
+/// Whether a static asset benefits from compression, fonts and raster
+/// images are already compressed.
+pub fn is_compressible(mime: &mime_guess::Mime) -> bool {
+    use mime_guess::mime;
+
+    mime.type_() == mime::TEXT
+        || mime.subtype() == mime::JAVASCRIPT
+        || mime.subtype() == mime::JSON
+        || mime.subtype() == mime::SVG
+}
+
+/// Compress an embedded static asset with maximum effort, once per process.
+///
+/// Compression runs on the blocking pool, the map of results is only locked
+/// to look them up and store them.
+pub async fn precompressed(path: &str, data: Cow<'static, [u8]>, encoding: Encoding) -> std::io::Result<Bytes> {
+    static PRECOMPRESSED: OnceLock<Mutex<HashMap<(String, Encoding), Bytes>>> = OnceLock::new();
+    let map = || {
+        PRECOMPRESSED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    };
+
+    let key = (path.to_string(), encoding);
+    if let Some(res) = map().get(&key).cloned() {
+        return Ok(res);
+    }
+
+    let res = tokio::task::spawn_blocking(move || encoding.compress(&data))
+        .await
+        .map_err(std::io::Error::other)??;
+    let res = Bytes::from(res);
+    map().insert(key, res.clone());
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept: &str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, accept.parse().unwrap());
+        Encoding::negotiate(&headers)
+    }
+
+    #[test]
+    fn q_values() {
+        assert_eq!(negotiate("gzip, deflate, br, zstd"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.2, zstd;q=0.8, gzip;q=0.5"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("GZIP ; q=0.3"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate"), None);
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, zstd;q=0, gzip;q=0"), None);
+        assert_eq!(negotiate("gzip;q=invalid"), None);
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0, *"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip, *;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn identity() {
+        // ties go to compression
+        assert_eq!(negotiate("identity, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, identity;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, identity"), None);
+        assert_eq!(negotiate("identity"), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! `ETag` / `Last-Modified` validators and conditional GET.
+
+use crate::utils::{db_last_changed, Error, Ext};
+use axum::body::{boxed, Full};
+use axum::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+use tracing::error;
+
+/// Middleware attaching validators to successful GET responses.
+///
+/// The `ETag` is a SHA-256 prefix of the body, which stays the same across
+/// builds. It is weak so that it still matches once the body has been
+/// compressed on the way out. Views also carry the time the data last
+/// changed as `Last-Modified`, see [`db_last_changed`].
+pub async fn add_validators<B>(db: Ext, req: Request<B>, next: Next<B>) -> Response {
+    let is_static = req.uri().path().starts_with("/static/");
+    let is_get = req.method() == Method::GET;
+
+    let res = next.run(req).await;
+    if !is_get || res.status() != StatusCode::OK || res.headers().contains_key(header::ETAG) {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return Error::Anyhow(anyhow::anyhow!(e.to_string())).into_response(),
+    };
+
+    let digest = Sha256::digest(&body);
+    if let Ok(etag) = format!("W/\"{}\"", hex::encode(&digest[..16])).parse::<ETag>() {
+        parts.headers.typed_insert(etag);
+    }
+
+    if !is_static {
+        match db_last_changed(db).await {
+            Ok(last_modified) => parts
+                .headers
+                .typed_insert(LastModified::from(SystemTime::from(last_modified))),
+            Err(e) => error!("failed to get last modified time: {:?}", e),
+        }
+    }
+
+    Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+/// Middleware answering `If-None-Match` and `If-Modified-Since` with
+/// `304 Not Modified` when the validators of the response match.
+pub async fn not_modified<B>(req: Request<B>, next: Next<B>) -> Response {
+    let if_none_match: Option<IfNoneMatch> = req.headers().typed_get();
+    let if_modified_since: Option<IfModifiedSince> = req.headers().typed_get();
+    let is_get = matches!(*req.method(), Method::GET | Method::HEAD);
+
+    let res = next.run(req).await;
+    if !is_get || res.status() != StatusCode::OK {
+        return res;
+    }
+
+    let etag: Option<ETag> = res.headers().typed_get();
+    let last_modified: Option<LastModified> = res.headers().typed_get();
+
+    // If-Modified-Since is ignored when If-None-Match is present, see RFC 9110 13.1.3
+    let fresh = match (if_none_match, etag, if_modified_since, last_modified) {
+        (Some(if_none_match), Some(etag), _, _) => !if_none_match.precondition_passes(&etag),
+        (None, _, Some(if_modified_since), Some(last_modified)) => !if_modified_since.is_modified(last_modified.into()),
+        _ => false,
+    };
+
+    if !fresh {
+        return res;
+    }
+
+    let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+    for name in [header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL, header::VARY] {
+        if let Some(value) = res.headers().get(&name) {
+            not_modified.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    not_modified
+}
@@ -1,33 +1,501 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use schemars::gen::SchemaSettings;
+use schemars::schema::{InstanceType, Schema, SingleOrVec};
+use schemars::{JsonSchema, Map};
 use serde::Deserialize;
-use std::{fs::File, io::Read, path::Path};
-#[derive(Debug, Clone, Deserialize, Default)]
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Prefix of the environment variables overriding `config.toml`, followed by
+/// the section and the field, e.g. `PACKAGES_SITE_DB_META_CONN`.
+pub const ENV_PREFIX: &str = "PACKAGES_SITE_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema, Default)]
 pub struct Config {
     pub db: Db,
     pub global: Global,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub webhook: Webhook,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub health: Health,
+    /// `PACKAGES_SITE_*` variables matching no setting, logged once logging
+    /// is set up
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub unknown_env: Vec<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema, Default)]
 pub struct Db {
     pub pv_conn: String,
     pub meta_conn: String,
+    /// read-only copies of `meta_conn`, queried in turn with it
+    #[serde(default)]
+    pub meta_replicas: Vec<String>,
+    /// upper bound of connections per database
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    /// connections kept open per database
+    #[serde(default)]
+    pub min_connections: u32,
+    /// seconds a query waits for a free connection before failing
+    #[serde(default = "default_acquire_timeout")]
+    pub acquire_timeout: u64,
+    /// seconds before an unused connection is closed, 0 to keep them
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// milliseconds a statement may run before postgres cancels it, 0 for no limit
+    #[serde(default)]
+    pub statement_timeout: u64,
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_acquire_timeout() -> u64 {
+    30
+}
+
+fn default_idle_timeout() -> u64 {
+    600
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema, Default)]
 pub struct Global {
     pub listen: String,
     pub log: String,
     pub sqlx_log: String,
     /// OpenTelemetry url
     pub otlp_url: Option<String>,
+    /// public url of the site, used for absolute links in feeds
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    /// seconds in-flight requests may take to finish after SIGTERM or SIGINT
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// permissions of unix sockets, e.g. `0o660`
+    pub socket_mode: Option<u32>,
+    /// owner of unix sockets
+    pub socket_uid: Option<u32>,
+    /// group of unix sockets
+    pub socket_gid: Option<u32>,
+}
+
+fn default_base_url() -> String {
+    "https://packages.aosc.io".into()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Cache {
+    pub enabled: bool,
+    /// upper bound of cached data in bytes
+    pub max_size: usize,
+    /// seconds before an entry expires even if the database is unchanged
+    pub ttl: u64,
+    /// seconds between two freshness probes
+    pub probe_interval: u64,
+    /// query on meta db returning one text value that changes with its data
+    pub probe: String,
+    /// same as `probe`, run against pv db, empty to only probe meta
+    pub pv_probe: Option<String>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: 64 * 1024 * 1024,
+            ttl: 600,
+            probe_interval: 10,
+            probe: "SELECT concat_ws(',', (SELECT max(commit_time) FROM package_versions), \
+                    (SELECT max(date) FROM dpkg_repos))"
+                .into(),
+            pv_probe: Some(
+                "SELECT concat_ws(',', (SELECT max(debtime) FROM pv_packages), count(*)) FROM pv_packages".into(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Compression {
+    /// disable when running behind a compressing reverse proxy
+    pub enabled: bool,
+    /// compression level for dynamic responses, each algorithm's default if unset
+    pub level: Option<u32>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            level: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Webhook {
+    pub enabled: bool,
+    /// every url receives a POST for each batch of events
+    pub urls: Vec<String>,
+    /// key of the HMAC-SHA256 `X-Packages-Signature` header
+    pub secret: Option<String>,
+    /// seconds between two scans of the database
+    pub interval: u64,
+    /// attempts per delivery before giving up
+    pub max_attempts: u32,
+    /// seconds before the first retry, doubled for each further attempt
+    pub retry_delay: u64,
+    /// file every delivery attempt is appended to as a json line, empty to disable
+    pub delivery_log: String,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            urls: vec![],
+            secret: None,
+            interval: 300,
+            max_attempts: 5,
+            retry_delay: 10,
+            delivery_log: "webhook.log".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema, Default)]
+#[serde(default)]
+pub struct Metrics {
+    /// serve prometheus metrics at `/metrics`
+    pub enabled: bool,
+    /// separate listener for `/metrics`, same syntax as `global.listen`, the main one if unset
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Health {
+    /// seconds since the last commit before `/readyz` fails, 0 to disable
+    pub max_data_age: u64,
+    /// seconds each `/readyz` check may take
+    pub timeout: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            max_data_age: 0,
+            timeout: 5,
+        }
+    }
+}
+
+impl Global {
+    /// `tracing_subscriber::EnvFilter` directives built from `log` and `sqlx_log`
+    pub fn log_filter(&self) -> String {
+        format!(
+            "tower_http::trace=trace,packages_site={log},sqlx::query={sqlx_log}",
+            log = self.log,
+            sqlx_log = self.sqlx_log
+        )
+    }
 }
 
 impl Config {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
-        let mut file = File::open(path)?;
-        let mut toml_str = String::new();
-        file.read_to_string(&mut toml_str)?;
-        let config: Config = toml::from_str(&toml_str)?;
+    /// Read `path`, apply the `PACKAGES_SITE_*` environment variables and
+    /// validate the result. A missing file is fine when the environment
+    /// provides every required field.
+    pub fn load(path: &str) -> Result<Config> {
+        let mut table = match std::fs::read_to_string(path) {
+            Ok(toml_str) => toml::from_str(&toml_str).with_context(|| format!("failed to parse {path}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => return Err(anyhow!(e).context(format!("failed to read {path}"))),
+        };
+        let unknown_env = apply_env(&mut table, std::env::vars())?;
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("invalid configuration in {path} or {ENV_PREFIX}* variables"))?;
+        config.validate()?;
+        config.unknown_env = unknown_env;
+
         Ok(config)
     }
+
+    /// Check the values serde cannot, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                errors.push(format!("{field}: {message}"));
+            }
+        };
+
+        check(
+            is_listen_address(&self.global.listen),
+            "global.listen",
+            format!(
+                "`{}` is neither an address such as 0.0.0.0:3000 nor unix:/path",
+                self.global.listen
+            ),
+        );
+        if let Some(listen) = &self.metrics.listen {
+            check(
+                is_listen_address(listen),
+                "metrics.listen",
+                format!("`{listen}` is neither an address such as 127.0.0.1:9100 nor unix:/path"),
+            );
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(self.global.log_filter()) {
+            check(
+                false,
+                "global.log",
+                format!("invalid log level in `log` or `sqlx_log`: {e}"),
+            );
+        }
+        if let Err(e) = reqwest::Url::parse(&self.global.base_url) {
+            check(
+                false,
+                "global.base_url",
+                format!("`{}` is not a url: {e}", self.global.base_url),
+            );
+        }
+        if let Some(mode) = self.global.socket_mode {
+            check(
+                mode <= 0o7777,
+                "global.socket_mode",
+                format!("{mode:#o} is not a file mode"),
+            );
+        }
+
+        let replicas = self.db.meta_replicas.iter().map(|conn| ("db.meta_replicas", conn));
+        for (field, conn) in [("db.meta_conn", &self.db.meta_conn), ("db.pv_conn", &self.db.pv_conn)]
+            .into_iter()
+            .chain(replicas)
+        {
+            if let Err(e) = sqlx::postgres::PgConnectOptions::from_str(conn) {
+                check(false, field, format!("not a postgres connection string: {e}"));
+            }
+        }
+        check(
+            self.db.max_connections > 0,
+            "db.max_connections",
+            "must be positive".into(),
+        );
+        check(
+            self.db.min_connections <= self.db.max_connections,
+            "db.min_connections",
+            format!("more than max_connections ({})", self.db.max_connections),
+        );
+        check(
+            self.db.acquire_timeout > 0,
+            "db.acquire_timeout",
+            "must be positive".into(),
+        );
+
+        if self.cache.enabled {
+            check(self.cache.max_size > 0, "cache.max_size", "must be positive".into());
+            check(
+                !self.cache.probe.trim().is_empty(),
+                "cache.probe",
+                "must not be empty".into(),
+            );
+        }
+
+        if self.webhook.enabled {
+            check(
+                !self.webhook.urls.is_empty(),
+                "webhook.urls",
+                "no url to deliver to".into(),
+            );
+            for url in &self.webhook.urls {
+                if let Err(e) = reqwest::Url::parse(url) {
+                    check(false, "webhook.urls", format!("`{url}` is not a url: {e}"));
+                }
+            }
+            check(self.webhook.interval > 0, "webhook.interval", "must be positive".into());
+            check(
+                self.webhook.max_attempts > 0,
+                "webhook.max_attempts",
+                "must be positive".into(),
+            );
+        }
+
+        check(self.health.timeout > 0, "health.timeout", "must be positive".into());
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            bail!("invalid configuration:\n  {}", errors.join("\n  "))
+        }
+    }
+}
+
+fn is_listen_address(listen: &str) -> bool {
+    listen.starts_with("unix:") || listen.parse::<SocketAddr>().is_ok()
+}
+
+/// Set `table.section.field` for every `PACKAGES_SITE_SECTION_FIELD`
+/// variable. Values of string fields are taken as is, others are parsed as
+/// TOML, e.g. `true`, `30` or `["http://a", "http://b"]`. Returns the
+/// variables that match no setting, which are left out.
+fn apply_env(table: &mut toml::Table, vars: impl IntoIterator<Item = (String, String)>) -> Result<Vec<String>> {
+    let schema = SchemaSettings::default()
+        .with(|s| s.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<Config>();
+    let sections = schema.schema.object.map(|o| o.properties).unwrap_or_default();
+
+    let mut unknown = vec![];
+    for (name, raw) in vars {
+        let key = if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+            key.to_lowercase()
+        } else {
+            continue;
+        };
+
+        let found = sections.iter().find_map(|(section, schema)| {
+            let field = key.strip_prefix(section.as_str())?.strip_prefix('_')?;
+            let schema = properties(schema)?.get(field)?;
+            Some((section, field, schema))
+        });
+        let (section, field, schema) = if let Some(found) = found {
+            found
+        } else {
+            unknown.push(name);
+            continue;
+        };
+
+        let value = if accepts_string(schema) {
+            toml::Value::String(raw)
+        } else {
+            toml::from_str::<toml::Table>(&format!("value = {raw}"))
+                .ok()
+                .and_then(|mut t| t.remove("value"))
+                .ok_or_else(|| anyhow!("{name}: `{raw}` is not a valid value"))?
+        };
+
+        let section = table
+            .entry(section.as_str())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if let toml::Value::Table(section) = section {
+            section.insert(field.to_string(), value);
+        } else {
+            bail!("{name}: `{section}` is not a section in the config file");
+        }
+    }
+
+    Ok(unknown)
+}
+
+fn properties(schema: &Schema) -> Option<&Map<String, Schema>> {
+    match schema {
+        Schema::Object(o) => o.object.as_ref().map(|o| &o.properties),
+        Schema::Bool(_) => None,
+    }
+}
+
+fn accepts_string(schema: &Schema) -> bool {
+    let instance_type = match schema {
+        Schema::Object(o) => o.instance_type.as_ref(),
+        Schema::Bool(_) => None,
+    };
+
+    match instance_type {
+        Some(SingleOrVec::Single(t)) => **t == InstanceType::String,
+        Some(SingleOrVec::Vec(ts)) => ts.contains(&InstanceType::String),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn load(toml_str: &str, env: &[(&str, &str)]) -> Result<Config> {
+        let mut table = toml::from_str(toml_str)?;
+        apply_env(&mut table, vars(env))?;
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    #[test]
+    fn env_overrides() {
+        let config = load(
+            "[global]\nlisten = \"0.0.0.0:3000\"\nlog = \"info\"\nsqlx_log = \"info\"\n",
+            &[
+                ("PACKAGES_SITE_DB_META_CONN", "postgresql://meta"),
+                ("PACKAGES_SITE_DB_PV_CONN", "postgresql://pv"),
+                ("PACKAGES_SITE_GLOBAL_LISTEN", "unix:/run/packages.sock"),
+                ("PACKAGES_SITE_GLOBAL_SOCKET_MODE", "0o660"),
+                ("PACKAGES_SITE_CACHE_MAX_SIZE", "1024"),
+                ("PACKAGES_SITE_WEBHOOK_ENABLED", "true"),
+                ("PACKAGES_SITE_WEBHOOK_URLS", r#"["http://a", "http://b"]"#),
+                ("PACKAGES_SITE_WEBHOOK_SECRET", "12345"),
+                ("OTHER", "ignored"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.db.meta_conn, "postgresql://meta");
+        assert_eq!(config.global.listen, "unix:/run/packages.sock");
+        assert_eq!(config.global.socket_mode, Some(0o660));
+        assert_eq!(config.cache.max_size, 1024);
+        assert!(config.webhook.enabled);
+        assert_eq!(config.webhook.urls, ["http://a", "http://b"]);
+        assert_eq!(config.webhook.secret.as_deref(), Some("12345"));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn env_ignores_unknown() {
+        for var in ["PACKAGES_SITE_DB_META", "PACKAGES_SITE_NOPE_LOG"] {
+            let mut table = toml::Table::new();
+            let unknown = apply_env(&mut table, vars(&[(var, "x"), ("PACKAGES_SITE_CACHE_TTL", "5")])).unwrap();
+            assert_eq!(unknown, [var]);
+            assert_eq!(table.len(), 1);
+        }
+
+        let mut table = toml::Table::new();
+        assert!(apply_env(&mut table, vars(&[("PACKAGES_SITE_CACHE_TTL", "soon")])).is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let mut config = load(
+            "[db]\nmeta_conn = \"postgresql:///\"\npv_conn = \"postgresql:///\"\n\
+             [global]\nlisten = \"0.0.0.0:3000\"\nlog = \"info\"\nsqlx_log = \"info\"\n",
+            &[],
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        config.global.listen = "localhost".into();
+        config.db.pv_conn = "pv database".into();
+        config.webhook.enabled = true;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("global.listen: `localhost`"), "{err}");
+        assert!(err.contains("db.pv_conn: "), "{err}");
+        assert!(err.contains("webhook.urls: no url"), "{err}");
+    }
 }
@@ -1,84 +1,462 @@
-use crate::config::Config;
+use crate::cache::Cache;
+use crate::config::{self, Config};
+use crate::metrics::QueryTimer;
 use anyhow::Result;
-use axum::async_trait;
-use itertools::Itertools;
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{BoxStream, StreamExt};
+use schemars::JsonSchema;
 use serde::Serialize;
-use sqlx::{pool::PoolOptions, query::QueryAs, Database, Executor, FromRow, IntoArguments, Pool, Postgres};
+use sqlx::pool::{PoolConnection, PoolOptions};
+use sqlx::postgres::{PgArguments, PgConnectOptions, PgQueryResult, PgRow, PgStatement, PgTypeInfo};
+use sqlx::Describe;
+use sqlx::Either;
+use sqlx::{query::QueryAs, Arguments, Execute, Executor, FromRow, Pool, Postgres, Row};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
 
 pub struct Db {
-    pub meta: Pool<Postgres>,
-    pub pv: Pool<Postgres>,
+    pub meta: DbPool,
+    pub pv: DbPool,
+    pub cache: Cache,
 }
 
 const PAGESIZE: u32 = 60;
 
 impl Db {
     pub async fn open(config: &Config) -> Result<Self> {
-        let meta: Pool<Postgres> = PoolOptions::new().connect(&config.db.meta_conn).await?;
-        let pv = PoolOptions::new().connect_lazy(&config.db.pv_conn)?;
+        let db = &config.db;
+        let options = PoolOptions::new()
+            .max_connections(db.max_connections)
+            .min_connections(db.min_connections)
+            .acquire_timeout(Duration::from_secs(db.acquire_timeout))
+            .idle_timeout((db.idle_timeout > 0).then(|| Duration::from_secs(db.idle_timeout)));
 
-        Ok(Db { meta, pv })
+        let mut meta = vec![
+            options
+                .clone()
+                .connect_with(connect_options(&db.meta_conn, db)?)
+                .await?,
+        ];
+        for replica in &db.meta_replicas {
+            meta.push(options.clone().connect_lazy_with(connect_options(replica, db)?));
+        }
+        let pv = options.connect_lazy_with(connect_options(&db.pv_conn, db)?);
+
+        let cache = Cache::new(&config.cache);
+
+        Ok(Db {
+            meta: DbPool::new("meta", meta),
+            pv: DbPool::new("pv", vec![pv]),
+            cache,
+        })
+    }
+
+    /// Wait for borrowed connections to be returned, then close all pools.
+    pub async fn close(&self) {
+        for pool in self.meta.pools().iter().chain(self.pv.pools()) {
+            pool.close().await;
+        }
+    }
+}
+
+fn connect_options(conn: &str, config: &config::Db) -> Result<PgConnectOptions> {
+    let options: PgConnectOptions = conn.parse()?;
+    if config.statement_timeout > 0 {
+        Ok(options.options([("statement_timeout", config.statement_timeout)]))
+    } else {
+        Ok(options)
+    }
+}
+
+/// How long a pool that failed to hand out a connection is tried last.
+const UNREACHABLE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection pools of a database and its replicas, queried in turn. Records
+/// the duration of every query, see [`crate::metrics`].
+#[derive(Debug)]
+pub struct DbPool {
+    pub name: &'static str,
+    pools: Vec<Pool<Postgres>>,
+    /// when each pool last failed to hand out a connection
+    unreachable: Vec<Mutex<Option<Instant>>>,
+    next: AtomicUsize,
+}
+
+impl DbPool {
+    fn new(name: &'static str, pools: Vec<Pool<Postgres>>) -> Self {
+        Self {
+            name,
+            unreachable: pools.iter().map(|_| Mutex::new(None)).collect(),
+            pools,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The primary pool first, then the replicas.
+    pub fn pools(&self) -> &[Pool<Postgres>] {
+        &self.pools
+    }
+
+    fn unreachable(&self, i: usize) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.unreachable[i].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A connection for a group of queries that must see the same data, such
+    /// as a page and its total, from the next pool in turn.
+    pub async fn pin(&self) -> Result<Pinned, sqlx::Error> {
+        Ok(Pinned {
+            name: self.name,
+            conn: self.pick().await?,
+        })
+    }
+
+    /// A connection to the primary, for reads compared with earlier ones,
+    /// such as freshness probes and webhook scans, which replicas lagging by
+    /// different amounts would send back and forth in time.
+    pub async fn primary(&self) -> Result<Pinned, sqlx::Error> {
+        Ok(Pinned {
+            name: self.name,
+            conn: self.pools[0].acquire().await?,
+        })
+    }
+
+    /// A connection from the next pool in turn that hands one out. Pools that
+    /// recently failed to are only tried once all others have failed too.
+    async fn pick(&self) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+        let len = self.pools.len();
+        if len == 1 {
+            return self.pools[0].acquire().await;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let (reachable, unreachable): (Vec<_>, Vec<_>) = (0..len).map(|i| (start + i) % len).partition(|&i| {
+            self.unreachable(i)
+                .is_none_or(|since| since.elapsed() >= UNREACHABLE_BACKOFF)
+        });
+
+        let mut error = sqlx::Error::PoolClosed;
+        for i in reachable.into_iter().chain(unreachable) {
+            match self.pools[i].acquire().await {
+                Ok(conn) => {
+                    *self.unreachable(i) = None;
+                    return Ok(conn);
+                }
+                Err(e) => {
+                    warn!("{} database #{} failed to hand out a connection: {}", self.name, i, e);
+                    *self.unreachable(i) = Some(Instant::now());
+                    error = e;
+                }
+            }
+        }
+
+        Err(error)
+    }
+}
+
+impl<'p> Executor<'p> for &'p DbPool {
+    type Database = Postgres;
+
+    fn fetch_many<'e, 'q: 'e, E>(self, query: E) -> BoxStream<'e, Result<Either<PgQueryResult, PgRow>, sqlx::Error>>
+    where
+        'p: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let timer = QueryTimer::start(self.name, query.sql());
+
+        // the row stream borrows the connection, so the future owning both
+        // forwards the rows through a channel, and runs as long as it is polled
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let query = async move {
+            match self.pick().await {
+                Ok(mut conn) => {
+                    let mut rows = conn.fetch_many(query);
+                    while let Some(row) = rows.next().await {
+                        if tx.send(row).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+        };
+        let rows = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+
+        // the timer lives as long as the stream
+        futures_util::stream::select(query.into_stream().filter_map(|()| async { None }), rows)
+            .map(move |row| {
+                let _ = &timer;
+                row
+            })
+            .boxed()
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E>(self, query: E) -> BoxFuture<'e, Result<Option<PgRow>, sqlx::Error>>
+    where
+        'p: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let timer = QueryTimer::start(self.name, query.sql());
+        Box::pin(async move {
+            let res = match self.pick().await {
+                Ok(mut conn) => conn.fetch_optional(query).await,
+                Err(e) => Err(e),
+            };
+            drop(timer);
+            res
+        })
+    }
+
+    fn prepare_with<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+        parameters: &'e [PgTypeInfo],
+    ) -> BoxFuture<'e, Result<PgStatement<'q>, sqlx::Error>>
+    where
+        'p: 'e,
+    {
+        Box::pin(async move { self.pick().await?.prepare_with(sql, parameters).await })
+    }
+
+    fn describe<'e, 'q: 'e>(self, sql: &'q str) -> BoxFuture<'e, Result<Describe<Postgres>, sqlx::Error>>
+    where
+        'p: 'e,
+    {
+        Box::pin(async move { self.pick().await?.describe(sql).await })
+    }
+}
+
+/// A connection held for several queries, see [`DbPool::pin`]. Records the
+/// duration of every query like [`DbPool`].
+#[derive(Debug)]
+pub struct Pinned {
+    name: &'static str,
+    conn: PoolConnection<Postgres>,
+}
+
+impl<'c> Executor<'c> for &'c mut Pinned {
+    type Database = Postgres;
+
+    fn fetch_many<'e, 'q: 'e, E>(self, query: E) -> BoxStream<'e, Result<Either<PgQueryResult, PgRow>, sqlx::Error>>
+    where
+        'c: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let timer = QueryTimer::start(self.name, query.sql());
+
+        // the timer lives as long as the stream
+        (&mut *self.conn)
+            .fetch_many(query)
+            .map(move |row| {
+                let _ = &timer;
+                row
+            })
+            .boxed()
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E>(self, query: E) -> BoxFuture<'e, Result<Option<PgRow>, sqlx::Error>>
+    where
+        'c: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let timer = QueryTimer::start(self.name, query.sql());
+        let res = (&mut *self.conn).fetch_optional(query);
+        Box::pin(async move {
+            let res = res.await;
+            drop(timer);
+            res
+        })
+    }
+
+    fn prepare_with<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+        parameters: &'e [PgTypeInfo],
+    ) -> BoxFuture<'e, Result<PgStatement<'q>, sqlx::Error>>
+    where
+        'c: 'e,
+    {
+        (&mut *self.conn).prepare_with(sql, parameters)
+    }
+
+    fn describe<'e, 'q: 'e>(self, sql: &'q str) -> BoxFuture<'e, Result<Describe<Postgres>, sqlx::Error>>
+    where
+        'c: 'e,
+    {
+        (&mut *self.conn).describe(sql)
     }
 }
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Serialize, JsonSchema)]
 pub struct Page {
     pub cur: u32,
     pub max: u32,
     pub count: u32,
+    /// Token for `?cursor=` to continue a keyset listing, absent on the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
-#[async_trait]
-pub trait Paginator<'q, DB, O, A>
+/// How a listing is sliced, see [`fetch_page`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Paging {
+    /// every row at once (`?page=all`)
+    All,
+    /// numbered page, starting from 1 (`?page=n`)
+    Page(u32),
+    /// rows whose `name` sorts after the given one (`?cursor=`)
+    After(String),
+}
+
+/// Keyset cursors are opaque to clients, they just carry the last package name.
+pub fn encode_cursor(name: &str) -> String {
+    hex::encode(name)
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<String> {
+    String::from_utf8(hex::decode(cursor).ok()?).ok()
+}
+
+/// Fetch the slice of the query built by `query` selected by `paging`.
+///
+/// Numbered pages append `LIMIT`/`OFFSET` to the query, so the query's own
+/// `ORDER BY` decides what a page holds and only `PAGESIZE` rows are
+/// transferred. The total is counted by a second statement, which is skipped
+/// when a partial page already tells where the listing ends.
+///
+/// Keyset pages require the query to return a `name` column; they are
+/// ordered by it and stay stable while the underlying tables change. Their
+/// total is counted as well, unless the first chunk already holds every row.
+///
+/// Rows and total are read over one connection, so that they agree even
+/// when replicas lag behind.
+pub async fn fetch_page<'q, O, F>(pool: &DbPool, paging: Paging, query: F) -> Result<(Vec<O>, Page), sqlx::Error>
 where
-    DB: Database,
-    A: 'q + IntoArguments<'q, DB>,
-    O: Send + Unpin + for<'r> FromRow<'r, DB::Row>,
+    O: Send + Unpin + for<'r> FromRow<'r, PgRow>,
+    F: Fn() -> QueryAs<'q, Postgres, O, PgArguments>,
 {
-    async fn fetch_page<'e, 'c: 'e, E>(self, executor: E, cur: Option<u32>) -> Result<(Vec<O>, Page), sqlx::Error>
-    where
-        'q: 'e,
-        Self: Sized,
-        E: 'e + Executor<'c, Database = DB>,
-        DB: 'e,
-        O: 'e,
-        A: 'e;
+    let conn = &mut pool.pin().await?;
+
+    let cur = match paging {
+        Paging::All => {
+            let v = query().fetch_all(&mut *conn).await?;
+            let count = v.len() as u32;
+            return Ok((
+                v,
+                Page {
+                    cur: 1,
+                    count,
+                    ..Default::default()
+                },
+            ));
+        }
+        Paging::After(after) => {
+            let first = after.is_empty();
+            let mut chunk = query();
+            let mut args = chunk.take_arguments().map_err(sqlx::Error::Encode)?.unwrap_or_default();
+            let sql = format!(
+                "SELECT * FROM ({}) AS q WHERE q.name > ${} ORDER BY q.name LIMIT {}",
+                chunk.sql(),
+                args.len() + 1,
+                PAGESIZE + 1
+            );
+            args.add(after).map_err(sqlx::Error::Encode)?;
+
+            let mut rows = conn.fetch_all((sql.as_str(), Some(args))).await?;
+            let next_cursor = if rows.len() > PAGESIZE as usize {
+                rows.truncate(PAGESIZE as usize);
+                let last = rows.last().map(|row| row.try_get::<String, _>("name")).transpose()?;
+                last.map(|name| encode_cursor(&name))
+            } else {
+                None
+            };
+            let res = rows.iter().map(O::from_row).collect::<Result<Vec<_>, _>>()?;
+
+            // a single chunk from the start is the whole listing
+            let count = if first && next_cursor.is_none() {
+                res.len() as u32
+            } else {
+                count_all(&mut *conn, query()).await?
+            };
+
+            return Ok((
+                res,
+                Page {
+                    cur: 1,
+                    max: count.div_ceil(PAGESIZE),
+                    count,
+                    next_cursor,
+                },
+            ));
+        }
+        Paging::Page(cur) => cur.max(1),
+    };
+
+    // bound rather than formatted in, so that every page shares one prepared
+    // statement
+    let offset = (cur as i64 - 1) * PAGESIZE as i64;
+    let mut page = query();
+    let mut args = page.take_arguments().map_err(sqlx::Error::Encode)?.unwrap_or_default();
+    let sql = format!("{} LIMIT ${} OFFSET ${}", page.sql(), args.len() + 1, args.len() + 2);
+    args.add(PAGESIZE as i64).map_err(sqlx::Error::Encode)?;
+    args.add(offset).map_err(sqlx::Error::Encode)?;
+    let rows = conn.fetch_all((sql.as_str(), Some(args))).await?;
+    let res = rows.iter().map(O::from_row).collect::<Result<Vec<_>, _>>()?;
+
+    let len = res.len() as u32;
+    let count = if len == PAGESIZE || (len == 0 && cur > 1) {
+        count_all(&mut *conn, query()).await?
+    } else {
+        (cur - 1) * PAGESIZE + len
+    };
+
+    let max = count.div_ceil(PAGESIZE);
+
+    Ok((
+        res,
+        Page {
+            cur,
+            max,
+            count,
+            ..Default::default()
+        },
+    ))
 }
 
-#[async_trait]
-impl<'q, DB, O, A> Paginator<'q, DB, O, A> for QueryAs<'q, DB, O, A>
+/// Number of rows `query` returns across all pages.
+async fn count_all<'q, 'c, O, E>(
+    executor: E,
+    mut query: QueryAs<'q, Postgres, O, PgArguments>,
+) -> Result<u32, sqlx::Error>
 where
-    DB: Database,
-    A: 'q + IntoArguments<'q, DB>,
-    O: Send + Unpin + for<'r> FromRow<'r, DB::Row>,
+    O: Send + Unpin + for<'r> FromRow<'r, PgRow>,
+    E: Executor<'c, Database = Postgres>,
 {
-    async fn fetch_page<'e, 'c: 'e, E>(mut self, executor: E, cur: Option<u32>) -> Result<(Vec<O>, Page), sqlx::Error>
-    where
-        'q: 'e,
-        Self: Sized,
-        E: 'e + Executor<'c, Database = DB>,
-        DB: 'e,
-        O: 'e,
-        A: 'e,
-    {
-        let v = self.fetch_all(executor).await?;
-        let count = v.len() as u32;
-        let ceil = |a, b| (a + b - 1) / b;
-
-        let (res, page) = if let Some(cur) = cur {
-            let res = v
-                .into_iter()
-                .chunks(PAGESIZE as usize)
-                .into_iter()
-                .nth((cur - 1) as usize)
-                .map_or(vec![], |i| i.collect_vec());
-            let max = ceil(count, PAGESIZE);
-
-            (res, Page { cur, max, count })
-        } else {
-            (v, Page { cur: 1, max: 0, count })
-        };
+    let sql = format!("SELECT count(*) FROM ({}) AS q", query.sql());
+    let args = query.take_arguments().map_err(sqlx::Error::Encode)?;
+    let row = executor.fetch_one((sql.as_str(), args)).await?;
+    Ok(row.try_get::<i64, _>(0)? as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        for name in ["", "bash", "libfoo+bar-1.2", "中文"] {
+            assert_eq!(decode_cursor(&encode_cursor(name)).as_deref(), Some(name));
+        }
+    }
 
-        Ok((res, page))
+    #[test]
+    fn invalid_cursor() {
+        // not hex, odd length, not utf-8
+        for cursor in ["zz", "abc", "ff"] {
+            assert_eq!(decode_cursor(cursor), None);
+        }
     }
 }
@@ -0,0 +1,73 @@
+//! Atom and RSS 2.0 renderings of update listings, selected with
+//! `?type=atom` or `?type=rss`.
+
+use crate::filters;
+use crate::utils::into_response;
+use askama::Template;
+use axum::response::Response;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Atom,
+    Rss,
+}
+
+impl Format {
+    pub fn from_type(t: Option<&str>) -> Option<Self> {
+        match t {
+            Some("atom") => Some(Format::Atom),
+            Some("rss") => Some(Format::Rss),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub title: String,
+    /// absolute url, also used as the entry id
+    pub link: String,
+    pub author: String,
+    pub summary: String,
+    pub updated: time::OffsetDateTime,
+}
+
+#[derive(Debug)]
+pub struct Feed {
+    pub title: String,
+    /// absolute url of the html page the feed mirrors
+    pub link: String,
+    /// absolute url of the feed itself
+    pub self_link: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Feed {
+    pub fn render(&self, format: Format) -> Response {
+        #[derive(Template)]
+        #[template(path = "feed.atom", escape = "html")]
+        struct Atom<'a> {
+            feed: &'a Feed,
+            updated: time::OffsetDateTime,
+        }
+
+        #[derive(Template)]
+        #[template(path = "feed.rss", escape = "html")]
+        struct Rss<'a> {
+            feed: &'a Feed,
+            updated: time::OffsetDateTime,
+        }
+
+        let updated = self
+            .entries
+            .iter()
+            .map(|e| e.updated)
+            .max()
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+
+        match format {
+            Format::Atom => into_response(&Atom { feed: self, updated }, Some("application/atom+xml")),
+            Format::Rss => into_response(&Rss { feed: self, updated }, Some("application/rss+xml")),
+        }
+    }
+}
@@ -22,6 +22,13 @@ pub fn fmt_timestamp(timestamp: &time::OffsetDateTime) -> ::askama::Result<Strin
     bail!("cannot format timestamp {timestamp} into RFC2822 format")
 }
 
+pub fn fmt_rfc3339(timestamp: &time::OffsetDateTime) -> ::askama::Result<String> {
+    if let Ok(date) = timestamp.format(&time::format_description::well_known::Rfc3339) {
+        return Ok(date);
+    }
+    bail!("cannot format timestamp {timestamp} into RFC3339 format")
+}
+
 pub fn cut(s: &str, len: usize) -> ::askama::Result<&str> {
     if s.len() <= len {
         Ok(s)
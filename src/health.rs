@@ -0,0 +1,171 @@
+//! Liveness and readiness probes, see `[health]` in `config.toml`.
+
+use crate::config;
+use crate::db::DbPool;
+use crate::openapi;
+use crate::sql::*;
+use crate::utils::*;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::future::Future;
+use std::time::Duration;
+
+/// Relations the pages rely on, a missing one means the collectors have not
+/// set up the database yet.
+const META_RELATIONS: &[&str] = &[
+    "packages",
+    "package_versions",
+    "v_packages",
+    "dpkg_packages",
+    "dpkg_repos",
+];
+const PV_RELATIONS: &[&str] = &["pv_packages", "pv_package_files", "pv_package_sodep", "v_so_breaks_dep"];
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Health {
+    /// `ok` or `fail`
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Check {
+    /// `meta`, `pv`, `meta_replica_1`, ... or `data_age`
+    pub name: String,
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Health {
+    fn new(checks: Vec<Check>) -> Self {
+        let ok = checks.iter().all(|c| c.ok);
+        Self {
+            status: if ok { "ok" } else { "fail" },
+            checks,
+        }
+    }
+}
+
+impl IntoResponse for Health {
+    fn into_response(self) -> Response {
+        let status = if self.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        match serde_json::to_string(&self) {
+            Ok(json) => (
+                status,
+                [(
+                    axum::http::header::CONTENT_TYPE,
+                    mime_guess::mime::APPLICATION_JSON.as_ref(),
+                )],
+                json,
+            )
+                .into_response(),
+            Err(e) => Error::from(e).into_response(),
+        }
+    }
+}
+
+/// Run a check, failing it when it takes longer than `timeout`.
+async fn check<F>(name: String, timeout: Duration, f: F) -> Check
+where
+    F: Future<Output = Result<String>>,
+{
+    let res = match tokio::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::Anyhow(anyhow::anyhow!("timed out after {timeout:?}"))),
+    };
+
+    match res {
+        Ok(message) => Check {
+            name,
+            ok: true,
+            message,
+        },
+        Err(e) => Check {
+            name,
+            ok: false,
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Check every pool of `db`, not just the one its round robin hands out next.
+async fn check_pools(db: &DbPool, relations: &[&str], timeout: Duration) -> Vec<Check> {
+    futures_util::future::join_all(db.pools().iter().enumerate().map(|(i, pool)| {
+        let name = match i {
+            0 => db.name.to_string(),
+            i => format!("{}_replica_{i}", db.name),
+        };
+        check(name, timeout, check_pool(pool, relations))
+    }))
+    .await
+}
+
+async fn check_pool(pool: &Pool<Postgres>, relations: &[&str]) -> Result<String> {
+    let missing: Vec<String> = sqlx::query_scalar(SQL_GET_MISSING_RELATIONS)
+        .bind(relations)
+        .fetch_all(pool)
+        .await?;
+
+    if missing.is_empty() {
+        Ok(format!("{} relations present", relations.len()))
+    } else {
+        Err(Error::Anyhow(anyhow::anyhow!("missing {}", missing.join(", "))))
+    }
+}
+
+async fn check_data_age(db: Ext, max_age: u64) -> Result<String> {
+    db.cache.refresh(&db).await?;
+    let age = time::OffsetDateTime::now_utc() - db_last_modified(db).await?;
+
+    if max_age == 0 || age.whole_seconds() <= max_age as i64 {
+        Ok(format!("last commit {}s ago", age.whole_seconds()))
+    } else {
+        Err(Error::Anyhow(anyhow::anyhow!(
+            "last commit {}s ago, more than {max_age}s",
+            age.whole_seconds()
+        )))
+    }
+}
+
+typed_path!("/healthz", Healthz);
+pub async fn healthz(_: Healthz) -> Health {
+    Health::new(vec![])
+}
+
+typed_path!("/readyz", Readyz);
+pub async fn readyz(_: Readyz, db: Ext, Extension(config): Extension<config::Health>) -> Health {
+    let timeout = Duration::from_secs(config.timeout);
+
+    let (meta, pv, data_age) = tokio::join!(
+        check_pools(&db.meta, META_RELATIONS, timeout),
+        check_pools(&db.pv, PV_RELATIONS, timeout),
+        check(
+            "data_age".to_string(),
+            timeout,
+            check_data_age(db.clone(), config.max_data_age)
+        ),
+    );
+
+    Health::new(meta.into_iter().chain(pv).chain([data_age]).collect())
+}
+
+impl openapi::Route<Health> for Healthz {
+    const SUMMARY: &'static str = "Whether the process is alive";
+}
+
+impl openapi::Route<Health> for Readyz {
+    const SUMMARY: &'static str = "Whether every database and replica is reachable and the data is recent, 503 if not";
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<Healthz, _>().route::<Readyz, _>()
+}
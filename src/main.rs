@@ -1,47 +1,60 @@
+mod cache;
+mod compression;
+mod conditional;
 mod config;
 mod db;
+mod feed;
 mod filters;
+mod health;
+mod metrics;
+mod openapi;
+mod reload;
+mod server;
 mod sql;
 mod utils;
 mod views;
+mod webhook;
 
 use anyhow::Result;
-use axum::{Extension, Router};
+use axum::{middleware, Extension, Router};
 use axum_extra::routing::RouterExt;
 use config::Config;
-use hyper::Server;
-use hyperlocal::UnixServerExt;
 use opentelemetry_otlp::WithExportConfig;
 use std::sync::Arc;
 use structopt::StructOpt;
 use tower_http::trace::DefaultOnResponse;
 use tower_http::trace::TraceLayer;
-use tracing::{info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::prelude::*;
 use utils::fallback;
 use views::*;
 
-const UNIX_SOCKET_PREFIX: &str = "unix:";
-
 #[derive(StructOpt, Debug)]
 #[structopt(name = "packages-site")]
 struct Opt {
     /// specify configuration file
     #[structopt(short, long, default_value = "config.toml")]
     config: String,
+    /// validate the configuration and exit
+    #[structopt(long)]
+    check_config: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let config = Config::from_file(opt.config)?;
+    let config = Config::load(&opt.config)?;
+    if opt.check_config {
+        for name in &config.unknown_env {
+            eprintln!("warning: ignoring {name}, it matches no setting");
+        }
+        println!("{}: configuration is valid", opt.config);
+        return Ok(());
+    }
 
     let subscriber = tracing_subscriber::Registry::default();
-    let env_filter = tracing_subscriber::EnvFilter::new(format!(
-        "tower_http::trace=trace,packages_site={log},sqlx::query={sqlx_log}",
-        log = config.global.log,
-        sqlx_log = config.global.sqlx_log
-    ));
+    let (env_filter, log_handle) =
+        tracing_subscriber::reload::Layer::new(tracing_subscriber::EnvFilter::new(config.global.log_filter()));
     if let Some(otlp_url) = &config.global.otlp_url {
         // setup otlp
         let exporter = opentelemetry_otlp::new_exporter().http().with_endpoint(otlp_url);
@@ -70,9 +83,21 @@ async fn main() -> Result<()> {
             .init();
     }
 
+    for name in &config.unknown_env {
+        warn!("ignoring {}, it matches no setting", name);
+    }
+
     let db = Arc::new(db::Db::open(&config).await?);
+    let signal = server::Signal::install()?;
+
+    let compression = compression::Enabled::new(config.compression.enabled);
+    reload::Reloader::new(opt.config, config.clone(), log_handle, db.clone(), compression.clone()).spawn()?;
 
-    let app = Router::new()
+    if config.metrics.enabled {
+        metrics::init()?;
+    }
+
+    let mut app = Router::new()
         .typed_get(static_files)
         .typed_get(changelog)
         .typed_get(index)
@@ -81,33 +106,78 @@ async fn main() -> Result<()> {
         .typed_get(lagging)
         .typed_get(missing)
         .typed_get(ghost)
+        .typed_get(broken)
+        .typed_get(conflicts)
         .typed_get(search)
+        .typed_get(filesearch)
         .typed_get(updates)
         .typed_get(repo)
+        .typed_get(repodiff)
+        .typed_get(matrix)
         .typed_get(packages)
         .typed_get(files)
+        .typed_get(diff)
         .typed_get(cleanmirror)
         .typed_get(revdep)
+        .typed_get(soname)
+        .typed_get(transition)
+        .typed_get(graph)
+        .typed_get(closure)
         .typed_get(license)
+        .typed_get(openapi_spec)
+        .typed_get(api_package)
+        .typed_get(api_repos)
+        .typed_get(api_repo)
+        .typed_get(api_changelog)
+        .typed_get(api_revdep)
+        .typed_get(api_files)
+        .typed_get(api_search)
         .fallback(fallback)
+        .layer(middleware::from_fn(utils::error_format))
+        .layer(middleware::from_fn(conditional::add_validators))
+        .layer(compression::layer(&config.compression))
+        .layer(middleware::from_fn(cache::cache_response))
+        .layer(middleware::from_fn(compression::toggle))
+        .layer(middleware::from_fn(conditional::not_modified))
         .layer(
             TraceLayer::new_for_http()
                 .on_request(())
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
-        .layer(Extension(db));
+        .layer(Extension(compression.clone()))
+        .layer(Extension(config.global.clone()))
+        .layer(Extension(db.clone()));
 
-    let service = app.into_make_service();
+    // probes must reach the databases, so they are kept out of the response cache
+    let health_app = Router::new()
+        .typed_get(health::healthz)
+        .typed_get(health::readyz)
+        .layer(Extension(config.health.clone()))
+        .layer(Extension(db.clone()));
+    app = app.merge(health_app);
 
-    let listen = &config.global.listen;
-    if let Some(socket) = listen.strip_prefix(UNIX_SOCKET_PREFIX) {
-        info!("package-site is listening on unix socket: {}", socket);
-        Server::bind_unix(socket)?.serve(service).await?;
-    } else {
-        let addr = listen.parse()?;
-        info!("package-site is listening on: {}", addr);
-        Server::bind(&addr).serve(service).await?;
+    if config.metrics.enabled {
+        // kept out of the response cache
+        let metrics_app = Router::new().typed_get(metrics::serve).layer(Extension(db.clone()));
+        app = app.layer(middleware::from_fn(metrics::track));
+
+        if let Some(listen) = config.metrics.listen.clone() {
+            let global = config.global.clone();
+            let signal = signal.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server::serve(&listen, metrics_app, &global, signal).await {
+                    error!("metrics listener failed: {:?}", e);
+                }
+            });
+        } else {
+            app = app.merge(metrics_app);
+        }
     }
 
+    server::serve(&config.global.listen, app, &config.global, signal).await?;
+
+    db.close().await;
+    info!("package-site stopped");
+
     Ok(())
 }
@@ -0,0 +1,174 @@
+//! Prometheus metrics, see `[metrics]` in `config.toml`.
+
+use crate::sql::QUERIES;
+use crate::utils::*;
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    query_duration: HistogramVec,
+    db_connections: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("packages_site".into()), None)?;
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests by route, method and status code"),
+            &["route", "method", "status"],
+        )?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time until the response head is ready"),
+            &["route"],
+        )?;
+        let query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time until all rows of a query are fetched, by `SQL_*` constant",
+            ),
+            &["pool", "query"],
+        )?;
+        let db_connections = IntGaugeVec::new(
+            Opts::new("db_connections", "Connections of each pool, by state"),
+            &["pool", "state"],
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        registry.register(Box::new(query_duration.clone()))?;
+        registry.register(Box::new(db_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            http_requests,
+            http_duration,
+            query_duration,
+            db_connections,
+        })
+    }
+}
+
+/// Start collecting, nothing is recorded before this is called.
+pub fn init() -> prometheus::Result<()> {
+    let _ = METRICS.set(Metrics::new()?);
+    Ok(())
+}
+
+/// Name of the `SQL_*` constant a statement was built from, `other` for
+/// inline queries.
+fn query_label(sql: &str) -> &'static str {
+    static LABELS: OnceLock<HashMap<&str, &str>> = OnceLock::new();
+    let labels = LABELS.get_or_init(|| QUERIES.iter().map(|(name, sql)| (*sql, *name)).collect());
+
+    if let Some(name) = labels.get(sql) {
+        return name;
+    }
+
+    // `db::fetch_page` appends `LIMIT`, and wraps queries in a subselect to
+    // count them or page them by keyset
+    let inner = sql
+        .strip_prefix("SELECT * FROM (")
+        .or_else(|| sql.strip_prefix("SELECT count(*) FROM ("))
+        .and_then(|s| s.rsplit_once(") AS q"))
+        .map(|(inner, _)| inner)
+        .or_else(|| sql.rsplit_once(" LIMIT ").map(|(inner, _)| inner));
+
+    inner.and_then(|inner| labels.get(inner)).copied().unwrap_or("other")
+}
+
+/// Records the duration of a query when dropped, that is once its rows are
+/// consumed or the query is abandoned.
+pub struct QueryTimer {
+    pool: &'static str,
+    query: &'static str,
+    start: Instant,
+}
+
+impl QueryTimer {
+    pub fn start(pool: &'static str, sql: &str) -> Option<Self> {
+        METRICS.get()?;
+
+        Some(Self {
+            pool,
+            query: query_label(sql),
+            start: Instant::now(),
+        })
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        if let Some(metrics) = METRICS.get() {
+            metrics
+                .query_duration
+                .with_label_values(&[self.pool, self.query])
+                .observe(self.start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Count requests and their latency per `typed_path!` route.
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "fallback".to_string(), |path| path.as_str().to_string());
+    let method = req.method().clone();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    if let Some(metrics) = METRICS.get() {
+        metrics
+            .http_requests
+            .with_label_values(&[&route, method.as_str(), res.status().as_str()])
+            .inc();
+        metrics
+            .http_duration
+            .with_label_values(&[&route])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    res
+}
+
+typed_path!("/metrics", RouteMetrics);
+pub async fn serve(_: RouteMetrics, db: Ext) -> Result<impl IntoResponse> {
+    let metrics = if let Some(metrics) = METRICS.get() {
+        metrics
+    } else {
+        not_found!("Metrics are disabled.");
+    };
+
+    for db in [&db.meta, &db.pv] {
+        let (mut size, mut idle, mut max) = (0, 0, 0);
+        for pool in db.pools() {
+            size += pool.size() as i64;
+            idle += pool.num_idle() as i64;
+            max += pool.options().get_max_connections() as i64;
+        }
+        for (state, value) in [("active", size - idle), ("idle", idle), ("max", max)] {
+            metrics.db_connections.with_label_values(&[db.name, state]).set(value);
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder
+        .encode(&metrics.registry.gather(), &mut buf)
+        .map_err(anyhow::Error::from)?;
+
+    build_resp(encoder.format_type().to_string(), axum::body::Full::from(buf))
+}
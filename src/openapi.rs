@@ -0,0 +1,143 @@
+//! OpenAPI 3 description of the JSON API, served at `/openapi.json`.
+//!
+//! Every handler implements [`Route`] for its typed path next to the
+//! context struct it renders, so the schema follows the handler around.
+
+use crate::utils::{ErrorBody, QueryExtractor};
+use axum_extra::routing::TypedPath;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// A route documented in the spec, `T` is what `?type=json` serializes.
+pub trait Route<T: JsonSchema>: TypedPath {
+    const SUMMARY: &'static str;
+    /// Fields of [`QueryExtractor`] read by the handler
+    const PARAMS: &'static [&'static str] = &[];
+    /// Content type of the response, only JSON responses get a schema
+    const MIME: &'static str = "application/json";
+}
+
+/// `time::OffsetDateTime` is serialized as `[year, ordinal day, hour, minute,
+/// second, nanosecond, offset hours, offset minutes, offset seconds]`.
+pub struct Timestamp;
+
+impl JsonSchema for Timestamp {
+    fn schema_name() -> String {
+        "Timestamp".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = <[i32; 9]>::json_schema(gen).into_object();
+        schema.metadata().description = Some(
+            "[year, ordinal day, hour, minute, second, nanosecond, offset hours, offset minutes, offset seconds]"
+                .to_string(),
+        );
+        schema.into()
+    }
+}
+
+pub struct Spec {
+    gen: SchemaGenerator,
+    query: Map<String, Value>,
+    paths: Map<String, Value>,
+}
+
+impl Spec {
+    pub fn new() -> Self {
+        // handlers reuse names such as `Template` and `Package`, inlining
+        // everything keeps them from clashing in `components`
+        let mut gen = SchemaSettings::openapi3()
+            .with(|s| s.inline_subschemas = true)
+            .into_generator();
+
+        let query = gen
+            .root_schema_for::<QueryExtractor>()
+            .schema
+            .object
+            .map(|o| o.properties.into_iter().map(|(k, v)| (k, json!(v))).collect())
+            .unwrap_or_default();
+
+        Self {
+            gen,
+            query,
+            paths: Map::new(),
+        }
+    }
+
+    pub fn route<P: Route<T>, T: JsonSchema>(mut self) -> Self {
+        let mut path = String::new();
+        let mut params = vec![];
+        for segment in P::PATH.split('/').skip(1) {
+            path.push('/');
+            match segment.strip_prefix(':').or_else(|| segment.strip_prefix('*')) {
+                Some(name) => {
+                    path.push_str(&format!("{{{name}}}"));
+                    let mut param = json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    });
+                    if segment.starts_with('*') {
+                        param["description"] = json!("May contain slashes");
+                    }
+                    params.push(param);
+                }
+                None => path.push_str(segment),
+            }
+        }
+
+        for name in P::PARAMS {
+            let mut schema = self.query.get(*name).cloned().unwrap_or_else(|| json!({}));
+            let mut param = json!({ "name": name, "in": "query" });
+            if let Some(description) = schema.as_object_mut().and_then(|s| s.remove("description")) {
+                param["description"] = description;
+            }
+            param["schema"] = schema;
+            params.push(param);
+        }
+
+        let mut content = Map::new();
+        if P::MIME == mime_guess::mime::APPLICATION_JSON.as_ref() {
+            content.insert(P::MIME.into(), json!({ "schema": self.gen.subschema_for::<T>() }));
+        } else {
+            content.insert(P::MIME.into(), json!({}));
+        }
+
+        self.paths.insert(
+            path,
+            json!({
+                "get": {
+                    "summary": P::SUMMARY,
+                    "parameters": params,
+                    "responses": {
+                        "200": { "description": "OK", "content": content },
+                        "4XX": {
+                            "description": "Error, as JSON for `?type=json` and `/api/v1`",
+                            "content": {
+                                "application/json": { "schema": self.gen.subschema_for::<ErrorBody>() },
+                            },
+                        },
+                    },
+                }
+            }),
+        );
+
+        self
+    }
+
+    pub fn finish(self, server: &str) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "AOSC OS Packages",
+                "version": env!("CARGO_PKG_VERSION"),
+                "description": "HTML pages are rendered as JSON with `?type=json` or the `X-Requested-With: XMLHttpRequest` header, the schemas below describe that form.",
+            },
+            "servers": [{ "url": server }],
+            "paths": self.paths,
+        })
+    }
+}
@@ -0,0 +1,131 @@
+//! Reload of the configuration on SIGHUP.
+//!
+//! Log filters, the `[cache]` and `[webhook]` sections and
+//! `compression.enabled` are applied in place, everything else needs a
+//! restart.
+
+use crate::compression;
+use crate::config::{self, Config};
+use crate::db::Db;
+use crate::webhook;
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+pub struct Reloader {
+    path: String,
+    config: Config,
+    log: LogHandle,
+    db: Arc<Db>,
+    compression: compression::Enabled,
+    webhook: Option<JoinHandle<()>>,
+    /// survives restarts of the webhook task
+    last_scan: Arc<webhook::LastScan>,
+}
+
+impl Reloader {
+    /// Take over the tasks driven by reloadable settings, starting them as
+    /// configured.
+    pub fn new(path: String, config: Config, log: LogHandle, db: Arc<Db>, compression: compression::Enabled) -> Self {
+        let mut reloader = Self {
+            path,
+            config,
+            log,
+            db,
+            compression,
+            webhook: None,
+            last_scan: Default::default(),
+        };
+        reloader.start_webhook();
+        reloader
+    }
+
+    fn start_webhook(&mut self) {
+        if let Some(task) = self.webhook.take() {
+            task.abort();
+        }
+
+        let webhook = &self.config.webhook;
+        if webhook.enabled {
+            info!("sending webhooks to {:?}", webhook.urls);
+            self.webhook = Some(tokio::spawn(webhook::run(
+                self.db.clone(),
+                webhook.clone(),
+                self.last_scan.clone(),
+            )));
+        } else {
+            // changes made while disabled are not reported once re-enabled
+            self.last_scan = Default::default();
+        }
+    }
+
+    /// Reload on every SIGHUP until the process exits.
+    pub fn spawn(mut self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("received SIGHUP, reloading {}", self.path);
+                if let Err(e) = self.reload() {
+                    error!("keeping the current configuration: {:?}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let new = Config::load(&self.path)?;
+        for name in &new.unknown_env {
+            warn!("ignoring {}, it matches no setting", name);
+        }
+        let old = std::mem::replace(&mut self.config, new.clone());
+
+        if old.global.log_filter() != new.global.log_filter() {
+            self.log.reload(EnvFilter::new(new.global.log_filter()))?;
+            info!("log filter is now {}", new.global.log_filter());
+        }
+        if old.cache != new.cache {
+            self.db.cache.reconfigure(&new.cache);
+            info!("cache settings reloaded");
+        }
+        if old.compression.enabled != new.compression.enabled {
+            self.compression.set(new.compression.enabled);
+            info!(
+                "compression is now {}",
+                if new.compression.enabled { "on" } else { "off" }
+            );
+        }
+        if old.webhook != new.webhook {
+            self.start_webhook();
+        }
+
+        let global = config::Global {
+            log: old.global.log.clone(),
+            sqlx_log: old.global.sqlx_log.clone(),
+            ..new.global.clone()
+        };
+        // the pools, listeners and middleware layers are built once in main,
+        // so these only warn
+        let restart = [
+            ("db", old.db != new.db),
+            ("global", old.global != global),
+            ("compression", old.compression.level != new.compression.level),
+            ("metrics", old.metrics != new.metrics),
+            ("health", old.health != new.health),
+        ];
+        for (section, changed) in restart {
+            if changed {
+                warn!("changes to [{}] take effect after a restart", section);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Listeners and graceful shutdown on SIGTERM and SIGINT.
+
+use crate::config;
+use anyhow::{bail, Result};
+use axum::Router;
+use hyper::Server;
+use hyperlocal::UnixServerExt;
+use std::fs::{self, Permissions};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Resolves once SIGTERM or SIGINT is received, one clone per listener.
+#[derive(Clone)]
+pub struct Signal(watch::Receiver<bool>);
+
+impl Signal {
+    pub fn install() -> Result<Self> {
+        let mut term = signal(SignalKind::terminate())?;
+        let mut int = signal(SignalKind::interrupt())?;
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = term.recv() => "SIGTERM",
+                _ = int.recv() => "SIGINT",
+            };
+            info!("received {}, shutting down", name);
+            let _ = tx.send(true);
+        });
+
+        Ok(Self(rx))
+    }
+
+    pub async fn recv(mut self) {
+        // the sender is only dropped after sending
+        let _ = self.0.wait_for(|stop| *stop).await;
+    }
+}
+
+/// Serve `app` on `listen` until `signal`, then give in-flight requests
+/// `global.shutdown_timeout` seconds to finish.
+pub async fn serve(listen: &str, app: Router, global: &config::Global, signal: Signal) -> Result<()> {
+    let service = app.into_make_service();
+    let timeout = Duration::from_secs(global.shutdown_timeout);
+
+    if let Some(socket) = listen.strip_prefix(UNIX_SOCKET_PREFIX) {
+        let socket = Path::new(socket);
+        remove_stale_socket(socket)?;
+
+        let server = Server::bind_unix(socket)?;
+        set_socket_permissions(socket, global)?;
+        info!("package-site is listening on unix socket: {}", socket.display());
+
+        let res = drain(
+            server.serve(service).with_graceful_shutdown(signal.clone().recv()),
+            signal,
+            timeout,
+        )
+        .await;
+        let _ = fs::remove_file(socket);
+        res
+    } else {
+        let addr = listen.parse()?;
+        let server = Server::try_bind(&addr)?;
+        info!("package-site is listening on: {}", addr);
+
+        drain(
+            server.serve(service).with_graceful_shutdown(signal.clone().recv()),
+            signal,
+            timeout,
+        )
+        .await
+    }
+}
+
+/// Wait for a server started with `with_graceful_shutdown`, dropping the
+/// connections still open `timeout` after the signal.
+async fn drain<F, E>(server: F, signal: Signal, timeout: Duration) -> Result<()>
+where
+    F: Future<Output = Result<(), E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    tokio::pin!(server);
+
+    tokio::select! {
+        res = &mut server => return Ok(res?),
+        _ = signal.recv() => {}
+    }
+
+    match tokio::time::timeout(timeout, server).await {
+        Ok(res) => Ok(res?),
+        Err(_) => {
+            warn!("requests still running after {:?}, dropping them", timeout);
+            Ok(())
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous run, unless a process still
+/// accepts connections on it.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        bail!("{} exists and is not a unix socket", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("{} is in use by another process", path.display());
+    }
+
+    info!("removing stale unix socket: {}", path.display());
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+fn set_socket_permissions(path: &Path, global: &config::Global) -> Result<()> {
+    if let Some(mode) = global.socket_mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    if global.socket_uid.is_some() || global.socket_gid.is_some() {
+        std::os::unix::fs::chown(path, global.socket_uid, global.socket_gid)?;
+    }
+    Ok(())
+}
@@ -1,3 +1,14 @@
+/// Declare the queries and collect them into `QUERIES`, so that metrics can
+/// be labelled with the constant a statement comes from.
+macro_rules! queries {
+    ($(pub const $name:ident: &str = $sql:expr;)*) => {
+        $(pub const $name: &str = $sql;)*
+
+        pub const QUERIES: &[(&str, &str)] = &[$((stringify!($name), $name)),*];
+    };
+}
+
+queries! {
 pub const SQL_GET_PACKAGE_TESTING: &str = "
 SELECT
     full_version,
@@ -229,6 +240,8 @@ WHERE
         FROM
             packages
 )
+ORDER BY
+    package
 ";
 
 pub const SQL_GET_PACKAGE_INFO_GHOST: &str = "
@@ -348,6 +361,8 @@ FROM
     ) error ON error.package = v_packages.name
 WHERE
     full_version IS NOT NULL and dpkg_version IS NOT NULL
+    AND ($2::text IS NULL OR tree = $2)
+    AND ($3::text IS NULL OR section = $3)
 ORDER BY
     commit_time DESC,
     name ASC
@@ -587,6 +602,44 @@ ORDER BY
     path, name
 "#;
 
+pub const SQL_SEARCH_FILES: &str = r#"
+SELECT
+    package,
+    version,
+    repo,
+    filename
+FROM
+    (
+        SELECT
+            f.package,
+            f.version,
+            f.repo,
+            (
+                CASE
+                    WHEN f.path = '' OR f.path = '.' THEN ''
+                    ELSE '/' || f.path
+                END
+            ) || '/' || f."name" filename
+        FROM
+            pv_package_files f
+            INNER JOIN v_packages_new n ON n.package = f.package
+            AND n.version = f.version
+            AND n.repo = f.repo
+        WHERE
+            f.ftype != 5
+            AND ($1::text IS NULL OR f."name" = $1)
+            AND ($2::text IS NULL OR f."name" LIKE $2)
+            AND ($3::text IS NULL OR f.path LIKE $3)
+            AND ($5::text IS NULL OR f.repo = $5)
+    ) q
+WHERE
+    ($4::text IS NULL OR filename ~ $4)
+ORDER BY
+    filename,
+    package,
+    repo
+"#;
+
 pub const SQL_GET_PACKAGE_SODEP: &str = "
 SELECT
     depends,
@@ -805,3 +858,367 @@ WHERE
 ORDER BY
     s.name, s.package
 ";
+
+pub const SQL_GET_SONAME_PACKAGES: &str = "
+SELECT
+    n.architecture,
+    s.depends,
+    s.name || coalesce(s.ver, '') soname,
+    s.package,
+    s.version,
+    s.repo
+FROM
+    pv_package_sodep s
+    INNER JOIN v_packages_new n ON n.package = s.package
+    AND n.version = s.version
+    AND n.repo = s.repo
+WHERE
+    s.name = $1
+    AND ($2 = '' OR s.ver = $2)
+ORDER BY
+    n.architecture,
+    s.depends,
+    s.package,
+    s.repo
+";
+
+pub const SQL_GET_SO_BREAKS_DEP_MULTI: &str = "
+SELECT
+    package,
+    dep_package,
+    deplist
+FROM
+    v_so_breaks_dep
+WHERE
+    package = ANY($1)
+";
+
+pub const SQL_GET_DEPENDENCY_EDGES: &str = r#"
+SELECT
+    package,
+    dependency,
+    relationship,
+    architecture,
+    coalesce(relop, '') || coalesce(version, '') AS version
+FROM
+    package_dependencies
+WHERE
+    (
+        package = ANY($1)
+        OR dependency = ANY($2)
+        OR (dependency LIKE '%|%' AND regexp_split_to_array(regexp_replace(dependency, '\s*\([^)]*\)', '', 'g'), '\s*\|\s*') && $2)
+    )
+    AND relationship = ANY($3)
+ORDER BY
+    package,
+    relationship,
+    dependency,
+    architecture
+"#;
+
+pub const SQL_GET_CLOSURE_LEVEL: &str = "
+SELECT
+    package,
+    dependency,
+    relationship,
+    architecture
+FROM
+    package_dependencies
+WHERE
+    (relationship = 'PKGDEP' AND package = ANY($1))
+    OR (relationship = 'PKGPROV' AND dependency = ANY($2))
+ORDER BY
+    package,
+    dependency
+";
+
+pub const SQL_GET_CLOSURE_SIZES: &str = "
+SELECT DISTINCT ON (dp.package)
+    dp.package,
+    dp.installed_size
+FROM
+    dpkg_packages dp
+    INNER JOIN dpkg_repos dr ON dr.name = dp.repo
+WHERE
+    dp.package = ANY($1)
+    AND dr.architecture IN ($2, 'noarch')
+ORDER BY
+    dp.package,
+    dr.testing,
+    dp._vercomp DESC
+";
+
+pub const SQL_GET_CLOSURE_ARCHS: &str = "
+SELECT DISTINCT
+    dr.architecture
+FROM
+    dpkg_packages dp
+    INNER JOIN dpkg_repos dr ON dr.name = dp.repo
+WHERE
+    ($1 = '' OR dp.package = $1)
+    AND dr.architecture != 'noarch'
+ORDER BY
+    dr.architecture
+";
+
+pub const SQL_GET_DEB_RELATIONS: &str = "
+SELECT
+    dp.package,
+    dp.version,
+    dp.repo,
+    coalesce(rel.depends, '') AS depends,
+    coalesce(rel.provides, '') AS provides
+FROM
+    dpkg_packages dp
+    INNER JOIN (
+        SELECT
+            package,
+            repo,
+            max_dpkgver(version) version
+        FROM
+            dpkg_packages
+        WHERE
+            repo = ANY($1)
+        GROUP BY
+            package,
+            repo
+    ) dpnew USING (package, version, repo)
+    LEFT JOIN (
+        SELECT
+            package,
+            version,
+            architecture,
+            repo,
+            string_agg(value, ', ') FILTER (
+                WHERE
+                    relationship IN ('Depends', 'Pre-Depends')
+            ) depends,
+            string_agg(value, ', ') FILTER (
+                WHERE
+                    relationship = 'Provides'
+            ) provides
+        FROM
+            dpkg_package_dependencies
+        WHERE
+            repo = ANY($1)
+        GROUP BY
+            package,
+            version,
+            architecture,
+            repo
+    ) rel ON rel.package = dp.package
+    AND rel.version = dp.version
+    AND rel.architecture = dp.architecture
+    AND rel.repo = dp.repo
+ORDER BY
+    dp.package
+";
+
+pub const SQL_GET_FILE_CONFLICTS: &str = r#"
+SELECT
+    filename,
+    array_agg(DISTINCT package ORDER BY package) packages
+FROM
+    (
+        SELECT
+            f.package,
+            (
+                CASE
+                    WHEN f.path = '' OR f.path = '.' THEN ''
+                    ELSE '/' || f.path
+                END
+            ) || '/' || f."name" filename
+        FROM
+            pv_package_files f
+            INNER JOIN v_packages_new n ON n.package = f.package
+            AND n.version = f.version
+            AND n.repo = f.repo
+        WHERE
+            f.ftype != 5
+            AND f.repo = ANY($1)
+    ) q
+GROUP BY
+    filename
+HAVING
+    count(DISTINCT package) > 1
+ORDER BY
+    filename
+"#;
+
+pub const SQL_GET_CONFLICT_RELATIONS: &str = "
+SELECT
+    package,
+    dependency,
+    relationship
+FROM
+    package_dependencies
+WHERE
+    relationship IN ('PKGCONFL', 'PKGBREAK', 'PKGREP')
+    AND package = ANY($1)
+    AND dependency = ANY($1)
+ORDER BY
+    package,
+    dependency
+";
+
+pub const SQL_GET_DEB_DEPENDENCIES: &str = "
+SELECT
+    relationship,
+    value
+FROM
+    dpkg_package_dependencies
+WHERE
+    package = $1
+    AND version = $2
+    AND repo = $3
+ORDER BY
+    relationship
+";
+
+pub const SQL_GET_REPO_VERSIONS: &str = r#"
+SELECT DISTINCT ON (package COLLATE "C")
+    package AS name,
+    dpkg_version
+FROM
+    v_dpkg_packages_new
+WHERE
+    repo = $1
+ORDER BY
+    package COLLATE "C",
+    _vercomp DESC
+"#;
+
+pub const SQL_GET_MATRIX_PACKAGES: &str = "
+SELECT
+    name,
+    tree,
+    tree_category,
+    section,
+    full_version,
+    (coalesce(spabhost.value, '') = 'noarch') noarch,
+    coalesce(spfailarch.value, '') fail_arch
+FROM
+    v_packages
+    LEFT JOIN package_spec spabhost ON spabhost.package = v_packages.name
+    AND spabhost.key = 'ABHOST'
+    LEFT JOIN package_spec spfailarch ON spfailarch.package = v_packages.name
+    AND spfailarch.key = 'FAIL_ARCH'
+WHERE
+    ($1::text IS NULL OR tree = $1)
+    AND ($2::text IS NULL OR section = $2)
+ORDER BY
+    name
+";
+
+pub const SQL_GET_MATRIX_DPKG: &str = "
+SELECT DISTINCT ON (dp.package, dr.realname)
+    dp.package,
+    dr.realname,
+    dp.version
+FROM
+    dpkg_packages dp
+    INNER JOIN dpkg_repos dr ON dr.name = dp.repo
+WHERE
+    dr.testing = 0
+    AND dp.package = ANY($1)
+ORDER BY
+    dp.package,
+    dr.realname,
+    dp._vercomp DESC
+";
+
+pub const SQL_GET_WEBHOOK_VERSIONS: &str = "
+SELECT
+    name,
+    full_version
+FROM
+    v_packages
+WHERE
+    full_version IS NOT NULL
+";
+
+pub const SQL_GET_WEBHOOK_DEBS: &str = "
+SELECT DISTINCT ON (package, repo)
+    package,
+    repo,
+    dpkg_version
+FROM
+    v_dpkg_packages_new
+ORDER BY
+    package,
+    repo,
+    _vercomp DESC
+";
+
+pub const SQL_GET_LAST_CHANGED: &str = "
+SELECT
+    greatest(
+        (SELECT max(commit_time) FROM package_versions),
+        (SELECT to_timestamp(max(date)) FROM dpkg_repos)
+    )
+";
+
+pub const SQL_GET_KNOWN_PACKAGES: &str = "
+SELECT
+    name
+FROM
+    packages
+WHERE
+    name = ANY($1)
+";
+
+pub const SQL_PACKAGE_EXISTS: &str = "
+SELECT
+    1
+FROM
+    packages
+WHERE
+    name = $1
+";
+
+pub const SQL_GET_PACKAGE_NAMES: &str = "
+SELECT
+    name
+FROM
+    packages
+";
+
+pub const SQL_GET_SOBREAKS: &str = "
+SELECT
+    dep_package,
+    deplist
+FROM
+    v_so_breaks_dep
+WHERE
+    package = $1
+";
+
+pub const SQL_GET_DEB_TIME: &str = "
+SELECT
+    debtime
+FROM
+    pv_packages
+WHERE
+    filename = $1
+";
+
+pub const SQL_GET_LAST_COMMIT: &str = "
+SELECT
+    commit_time
+FROM
+    package_versions
+ORDER BY
+    commit_time DESC
+LIMIT
+    1
+";
+
+pub const SQL_GET_MISSING_RELATIONS: &str = "
+SELECT
+    r
+FROM
+    unnest($1::text[]) r
+WHERE
+    to_regclass(r) IS NULL
+";
+}
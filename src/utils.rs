@@ -1,6 +1,12 @@
-use crate::db::Db;
+use crate::cache::Weight;
+use crate::db::{decode_cursor, Db, Paging};
+use crate::sql::SQL_GET_DEB_RELATIONS;
+use crate::sql::SQL_GET_LAST_CHANGED;
+use crate::sql::SQL_GET_LAST_COMMIT;
 use crate::sql::SQL_GET_REPO_COUNT;
 use crate::sql::SQL_GET_TREES;
+use abbs_meta_tree::package::FailArch;
+use anyhow::anyhow;
 use askama::Template;
 use axum::async_trait;
 use axum::extract::FromRequestParts;
@@ -10,16 +16,19 @@ use axum::http::request::Parts;
 use axum::http::HeaderValue;
 use axum::http::StatusCode;
 use axum::http::Uri;
+use axum::middleware::Next;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Extension;
 use indexmap::IndexMap;
+use itertools::Itertools;
 use proc_macro_regex::regex;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
 use sqlx::query_as;
 use sqlx::FromRow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tracing::error;
 
@@ -72,6 +81,37 @@ pub enum Error {
     Query(#[from] axum::extract::rejection::QueryRejection),
 }
 
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::NotSupported(_) => StatusCode::BAD_REQUEST,
+            Self::Query(e) => e.status(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::NotSupported(_) => "not_supported",
+            Self::Query(_) => "bad_query",
+            Self::Sqlx(_) => "database",
+            _ => "internal",
+        }
+    }
+}
+
+/// Machine readable form of an [`Error`], see [`error_format`].
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ErrorBody {
+    /// HTTP status code
+    pub code: u16,
+    /// `not_found`, `not_supported`, `bad_query`, `database` or `internal`
+    pub kind: &'static str,
+    pub message: String,
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         #[derive(Template)]
@@ -83,14 +123,68 @@ impl IntoResponse for Error {
         let ctx = Template {
             error: self.to_string(),
         };
-        let status_code = match self {
-            Self::NotFound(_) => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        let status_code = self.status_code();
+        let body = ErrorBody {
+            code: status_code.as_u16(),
+            kind: self.kind(),
+            message: match &self {
+                Self::NotFound(msg) | Self::NotSupported(msg) => msg.clone(),
+                Self::Query(e) => e.body_text(),
+                e => e.to_string(),
+            },
         };
 
         error!("Rendering error page with error {:?}", self);
 
-        (status_code, into_response(&ctx, None)).into_response()
+        let mut res = (status_code, into_response(&ctx, None)).into_response();
+        res.extensions_mut().insert(body);
+        res
+    }
+}
+
+/// Render error pages as JSON for `?type=json`, `XMLHttpRequest` and
+/// `/api/`, or as plain text for `?type=tsv` and `?type=dot`.
+pub async fn error_format<B>(req: http::Request<B>, next: Next<B>) -> Response {
+    let format = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("type="))
+        .map(String::from);
+    let json = req.uri().path().starts_with("/api/")
+        || format.as_deref() == Some("json")
+        || req
+            .headers()
+            .get("X-Requested-With")
+            .is_some_and(|h| h == "XMLHttpRequest");
+
+    let res = next.run(req).await;
+    let body = match res.extensions().get::<ErrorBody>() {
+        Some(body) => body,
+        None => return res,
+    };
+
+    if json {
+        match serde_json::to_string(body) {
+            Ok(json) => (
+                res.status(),
+                [(header::CONTENT_TYPE, mime_guess::mime::APPLICATION_JSON.as_ref())],
+                json,
+            )
+                .into_response(),
+            Err(_) => res,
+        }
+    } else if matches!(format.as_deref(), Some("tsv" | "dot")) {
+        let text = format!("{}: {}\n", body.kind, body.message);
+        (
+            res.status(),
+            [(header::CONTENT_TYPE, mime_guess::mime::TEXT_PLAIN.as_ref())],
+            text,
+        )
+            .into_response()
+    } else {
+        res
     }
 }
 
@@ -144,12 +238,43 @@ pub async fn fallback(uri: Uri) -> impl IntoResponse {
 
 pub type Query = QueryExtractor;
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
 pub struct QueryExtractor {
+    /// Page number, or `all` to disable paging
     page: Option<String>,
+    /// Keyset paging token, start with an empty one and follow `page.next_cursor`
+    cursor: Option<String>,
+    /// Search keywords
     q: Option<String>,
+    /// Repository such as `amd64/stable`
+    repo: Option<String>,
+    /// Comma separated package names
+    pkgs: Option<String>,
+    /// Levels of dependencies to walk, 1 to 5
+    depth: Option<u32>,
+    /// `forward`, `reverse` or `both`
+    direction: Option<String>,
+    /// Comma separated relationships such as `PKGDEP,BUILDDEP`
+    rel: Option<String>,
+    /// Only compute the given architecture
+    arch: Option<String>,
+    /// Build to compare from, as `repo/version`
+    from: Option<String>,
+    /// Build to compare to, as `repo/version`
+    to: Option<String>,
+    /// First repository to compare
+    a: Option<String>,
+    /// Second repository to compare
+    b: Option<String>,
+    /// Only packages in the given tree
+    tree: Option<String>,
+    /// Only packages in the given section
+    section: Option<String>,
+    /// Show search results even for an exact match
     noredir: Option<bool>,
+    /// Comma separated removal reasons
     reason: Option<String>,
+    /// Response format: `json`, `tsv` and, where supported, `dot`, `svg`, `atom` or `rss`
     r#type: Option<String>,
 }
 
@@ -186,22 +311,91 @@ impl QueryExtractor {
             None
         }
     }
-    #[inline(always)]
-    pub fn get_page(&self) -> Option<u32> {
-        if let Some(ref page) = self.page {
+    pub fn get_page(&self) -> Result<Paging> {
+        if let Some(ref cursor) = self.cursor {
+            return if cursor.is_empty() {
+                Ok(Paging::After(String::new()))
+            } else if let Some(after) = decode_cursor(cursor) {
+                Ok(Paging::After(after))
+            } else {
+                Err(Error::NotSupported(format!("invalid cursor \"{cursor}\"")))
+            };
+        }
+
+        Ok(if let Some(ref page) = self.page {
             match page.as_str() {
-                "all" => None,
-                s => Some(s.parse::<u32>().unwrap_or(1)),
+                "all" => Paging::All,
+                s => Paging::Page(s.parse::<u32>().unwrap_or(1)),
             }
         } else {
-            Some(1)
-        }
+            Paging::Page(1)
+        })
     }
 
     pub fn get_query(&self) -> &Option<String> {
         &self.q
     }
 
+    pub fn get_repo(&self) -> Option<&str> {
+        self.repo.as_deref().filter(|r| !r.is_empty())
+    }
+
+    /// Comma separated package names, deduplicated and in the given order
+    pub fn get_pkgs(&self) -> Vec<String> {
+        self.pkgs
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .unique()
+            .collect()
+    }
+
+    pub fn get_depth(&self) -> Option<u32> {
+        self.depth
+    }
+
+    pub fn get_direction(&self) -> Option<&str> {
+        self.direction.as_deref()
+    }
+
+    /// Comma separated relationships such as `PKGDEP,BUILDDEP`
+    pub fn get_rel(&self) -> Option<Vec<String>> {
+        self.rel
+            .as_deref()
+            .map(|r| r.split(',').map(|r| r.trim().to_uppercase()).collect())
+    }
+
+    pub fn get_arch(&self) -> Option<&str> {
+        self.arch.as_deref().filter(|a| !a.is_empty())
+    }
+
+    pub fn get_from(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn get_to(&self) -> Option<&str> {
+        self.to.as_deref()
+    }
+
+    pub fn get_a(&self) -> Option<&str> {
+        self.a.as_deref().filter(|a| !a.is_empty())
+    }
+
+    pub fn get_b(&self) -> Option<&str> {
+        self.b.as_deref().filter(|b| !b.is_empty())
+    }
+
+    pub fn get_tree(&self) -> Option<&str> {
+        self.tree.as_deref().filter(|t| !t.is_empty())
+    }
+
+    pub fn get_section(&self) -> Option<&str> {
+        self.section.as_deref().filter(|s| !s.is_empty())
+    }
+
     pub fn get_noredir(&self) -> bool {
         self.noredir.unwrap_or(false)
     }
@@ -230,15 +424,38 @@ pub async fn db_last_modified(db: Ext) -> Result<time::OffsetDateTime> {
         commit_time: time::OffsetDateTime,
     }
 
-    let res: Option<CommitTime> =
-        query_as("SELECT commit_time FROM package_versions ORDER BY commit_time DESC LIMIT 1")
-            .fetch_optional(&db.meta)
-            .await?;
+    let res = db
+        .cache
+        .get_or_try_insert_with(&db, "db_last_modified", || async {
+            let res: Option<CommitTime> = query_as(SQL_GET_LAST_COMMIT)
+                .fetch_optional(&mut db.meta.primary().await?)
+                .await?;
+
+            Ok(res.map(|t| t.commit_time).unwrap_or_else(time::OffsetDateTime::now_utc))
+        })
+        .await?;
+
+    Ok(*res)
+}
 
-    Ok(res.map(|t| t.commit_time).unwrap_or_else(time::OffsetDateTime::now_utc))
+/// When the data behind any page last changed: the last commit, or the last
+/// sync of a dpkg repo, which updates listings without a new commit.
+pub async fn db_last_changed(db: Ext) -> Result<time::OffsetDateTime> {
+    let res = db
+        .cache
+        .get_or_try_insert_with(&db, "db_last_changed", || async {
+            let res: Option<time::OffsetDateTime> = sqlx::query_scalar(SQL_GET_LAST_CHANGED)
+                .fetch_one(&mut db.meta.primary().await?)
+                .await?;
+
+            Ok(res.unwrap_or_else(time::OffsetDateTime::now_utc))
+        })
+        .await?;
+
+    Ok(*res)
 }
 
-#[derive(FromRow, Debug, Clone, Serialize)]
+#[derive(FromRow, Debug, Clone, Serialize, JsonSchema)]
 #[allow(unused)]
 pub struct Repo {
     pub name: String,
@@ -253,31 +470,186 @@ pub struct Repo {
     pub ghost: i32,
     pub lagging: i32,
     pub missing: i32,
+    /// Filled in from [`db_broken_counts`], not stored in `dpkg_repo_stats`.
+    #[sqlx(skip)]
+    pub broken: usize,
+}
+
+impl Weight for Repo {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.name.capacity()
+            + self.realname.capacity()
+            + self.architecture.capacity()
+            + self.branch.capacity()
+            + self.category.capacity()
+    }
 }
 
 pub async fn db_repos(db: &Ext) -> Result<IndexMap<String, Repo>> {
-    let repos: Vec<Repo> = query_as(SQL_GET_REPO_COUNT).fetch_all(&db.meta).await?;
+    let res: Arc<IndexMap<String, Repo>> = db
+        .cache
+        .get_or_try_insert_with(db, "db_repos", || async {
+            let repos: Vec<Repo> = query_as(SQL_GET_REPO_COUNT).fetch_all(&db.meta).await?;
 
-    let res = repos.into_iter().map(|repo| (repo.name.clone(), repo)).collect();
+            Ok(repos.into_iter().map(|repo| (repo.name.clone(), repo)).collect())
+        })
+        .await?;
 
-    Ok(res)
+    Ok((*res).clone())
 }
 
-#[derive(FromRow, Debug, Clone, Serialize)]
+/// Repo `realname`s a package of `tree_category` should be built for,
+/// leaving out the architectures excluded by `FAIL_ARCH`.
+pub fn expected_repos(repos: &IndexMap<String, Repo>, tree_category: &str, fail_arch: &str) -> Result<Vec<String>> {
+    let fail_arch = if fail_arch.is_empty() {
+        None
+    } else {
+        Some(FailArch::from(fail_arch).map_err(|_| anyhow!("invalid FAIL_ARCH format"))?)
+    };
+
+    Ok(repos
+        .values()
+        .filter(|r| {
+            let flag = match &fail_arch {
+                Some(FailArch::Include(v)) => !v.contains(&r.realname),
+                Some(FailArch::Exclude(v)) => v.contains(&r.realname),
+                None => true,
+            };
+            (r.category == tree_category) & (r.realname != "noarch") & flag
+        })
+        .map(|r| r.realname.clone())
+        .unique()
+        .sorted()
+        .collect())
+}
+
+/// `repo` and the `noarch` repo of the same branch, whose packages are
+/// installed alongside.
+pub async fn with_noarch(db: &Ext, repo: &Repo) -> Result<Vec<String>> {
+    let mut repos = vec![repo.name.clone()];
+    repos.extend(
+        db_repos(db)
+            .await?
+            .into_values()
+            .filter(|r| r.architecture == "noarch" && r.branch == repo.branch && r.name != repo.name)
+            .map(|r| r.name),
+    );
+
+    Ok(repos)
+}
+
+/// A dependency of the latest deb in a repo that nothing can satisfy.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct BrokenDep {
+    pub package: String,
+    pub version: String,
+    pub dependency: String,
+}
+
+impl Weight for BrokenDep {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>() + self.package.capacity() + self.version.capacity() + self.dependency.capacity()
+    }
+}
+
+/// Check the `Depends` of every latest deb in `repo` against the debs of
+/// the same repo and the `noarch` repo of the same branch.
+pub async fn db_broken(db: &Ext, repo: &Repo) -> Result<Arc<Vec<BrokenDep>>> {
+    #[derive(Debug, FromRow)]
+    struct Deb {
+        package: String,
+        version: String,
+        repo: String,
+        depends: String,
+        provides: String,
+    }
+
+    let key = format!("db_broken:{}", repo.name);
+    db.cache
+        .get_or_try_insert_with(db, &key, || async {
+            let repos = with_noarch(db, repo).await?;
+            let debs: Vec<Deb> = query_as(SQL_GET_DEB_RELATIONS).bind(&repos).fetch_all(&db.meta).await?;
+
+            let mut available: HashMap<&str, Vec<&str>> = HashMap::new();
+            let mut provided: HashMap<String, Vec<DebRelation>> = HashMap::new();
+            for deb in &debs {
+                available.entry(&deb.package).or_default().push(&deb.version);
+                for p in DebRelation::parse_field(&deb.provides).into_iter().flatten() {
+                    provided.entry(p.name.clone()).or_default().push(p);
+                }
+            }
+
+            let satisfied = |rel: &DebRelation| {
+                available
+                    .get(rel.name.as_str())
+                    .is_some_and(|versions| versions.iter().any(|v| rel.matches(v)))
+                    // only versioned provides satisfy versioned dependencies
+                    || provided.get(&rel.name).is_some_and(|provides| {
+                        provides
+                            .iter()
+                            .any(|p| rel.relop.is_empty() || (p.relop == "=" && rel.matches(&p.version)))
+                    })
+            };
+
+            let mut broken = vec![];
+            for deb in debs.iter().filter(|deb| deb.repo == repo.name) {
+                for group in deb.depends.split(',').map(str::trim).filter(|g| !g.is_empty()) {
+                    let alternatives = DebRelation::parse_alternatives(group);
+                    if !alternatives.iter().any(satisfied) {
+                        broken.push(BrokenDep {
+                            package: deb.package.clone(),
+                            version: deb.version.clone(),
+                            dependency: group.to_string(),
+                        });
+                    }
+                }
+            }
+
+            Ok(broken)
+        })
+        .await
+}
+
+/// Number of broken packages per repository, built from the cached
+/// [`db_broken`] lists so that the index only pays for them once.
+pub async fn db_broken_counts(db: &Ext) -> Result<Arc<IndexMap<String, usize>>> {
+    db.cache
+        .get_or_try_insert_with(db, "db_broken_counts", || async {
+            let repos = db_repos(db).await?;
+            let broken = futures_util::future::try_join_all(repos.values().map(|repo| db_broken(db, repo))).await?;
+            Ok(repos.into_keys().zip(broken.iter().map(|b| b.len())).collect())
+        })
+        .await
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, JsonSchema)]
 #[allow(unused)]
 pub struct Tree {
     pub name: String,
     pub category: String,
     pub url: String,
+    #[schemars(with = "crate::openapi::Timestamp")]
     pub date: time::OffsetDateTime,
     pub pkgcount: i64,
 }
+
+impl Weight for Tree {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>() + self.name.capacity() + self.category.capacity() + self.url.capacity()
+    }
+}
 pub async fn db_trees(db: &Ext) -> Result<IndexMap<String, Tree>> {
-    let trees: Vec<Tree> = query_as(SQL_GET_TREES).fetch_all(&db.meta).await?;
+    let res: Arc<IndexMap<String, Tree>> = db
+        .cache
+        .get_or_try_insert_with(db, "db_trees", || async {
+            let trees: Vec<Tree> = query_as(SQL_GET_TREES).fetch_all(&db.meta).await?;
 
-    let res = trees.into_iter().map(|tree| (tree.name.clone(), tree)).collect();
+            Ok(trees.into_iter().map(|tree| (tree.name.clone(), tree)).collect())
+        })
+        .await?;
 
-    Ok(res)
+    Ok((*res).clone())
 }
 
 pub fn ver_rel(ver_compare: i32) -> &'static str {
@@ -290,7 +662,7 @@ pub fn ver_rel(ver_compare: i32) -> &'static str {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct Dependency {
     pub relationship: String,
     pub arch: String,
@@ -341,6 +713,101 @@ impl Dependency {
     }
 }
 
+/// One alternative of a deb relationship field, e.g. `libc6 (>= 2.36)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebRelation {
+    pub name: String,
+    pub relop: String,
+    pub version: String,
+}
+
+impl DebRelation {
+    /// Parse a `Depends`-like field into groups of alternatives.
+    pub fn parse_field(s: &str) -> Vec<Vec<Self>> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|g| !g.is_empty())
+            .map(Self::parse_alternatives)
+            .collect()
+    }
+
+    pub fn parse_alternatives(s: &str) -> Vec<Self> {
+        s.split('|').filter_map(Self::parse).collect()
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let (name, cond) = s.split_once('(').unwrap_or((s, ""));
+        // multiarch qualifiers such as `python3:any`
+        let name = name.trim().split(':').next()?;
+        if name.is_empty() {
+            return None;
+        }
+
+        let cond = cond.split(')').next().unwrap_or("").trim();
+        let (relop, version) = cond.split_at(cond.find(|c| !matches!(c, '<' | '>' | '=')).unwrap_or(cond.len()));
+
+        Some(Self {
+            name: name.to_string(),
+            relop: relop.to_string(),
+            version: version.trim().to_string(),
+        })
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        use std::cmp::Ordering::*;
+
+        if self.relop.is_empty() {
+            return true;
+        }
+        let ord = deb_version::compare_versions(version, &self.version);
+        match self.relop.as_str() {
+            "<<" => ord == Less,
+            "<=" | "<" => ord != Greater,
+            "=" => ord == Equal,
+            ">=" | ">" => ord != Less,
+            ">>" => ord == Greater,
+            _ => false,
+        }
+    }
+}
+
+/// Sort packages affected by a library bump into rebuild layers.
+///
+/// `data` maps each package to its `deplist` from `v_so_breaks_dep`. Returns
+/// the layers in rebuild order and the packages caught in dependency cycles.
+pub fn toposort(mut data: HashMap<String, HashSet<String>>) -> (Vec<Vec<String>>, Vec<String>) {
+    let mut layers = vec![];
+
+    loop {
+        let ordered: HashSet<_> = data
+            .iter()
+            .filter(|(_, dep)| dep.is_empty())
+            .map(|(pkg, _)| pkg.to_string())
+            .collect();
+
+        if ordered.is_empty() {
+            break;
+        }
+
+        data = data
+            .into_iter()
+            .filter_map(|(item, dep)| {
+                if !ordered.contains(&item) {
+                    Some((item, &dep - &ordered))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        layers.push(ordered.into_iter().sorted().collect_vec());
+    }
+
+    let circular = data.into_keys().sorted().collect_vec();
+    layers.reverse();
+    (layers, circular)
+}
+
 pub struct Src {
     pub srcurl: String,
     pub srctype: SrcType,
@@ -391,7 +858,7 @@ impl Src {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, JsonSchema)]
 pub enum SrcType {
     Git,
     Tarball,
@@ -414,7 +881,7 @@ impl std::fmt::Display for SrcType {
 }
 
 pub const REPO_CAT: [(&str, &str); 3] = [("base", ""), ("bsp", "BSP"), ("overlay", "Overlay")];
-const DEP_REL: [(&str, &str); 8] = [
+pub const DEP_REL: [(&str, &str); 8] = [
     ("PKGDEP", "Depends"),
     ("BUILDDEP", "Depends (build)"),
     ("PKGREP", "Replaces"),
@@ -434,3 +901,60 @@ pub const DEP_REL_REV: [(&str, &str); 4] = [
 regex!(pub regex_srchost r"^https://(github\.com|bitbucket\.org|gitlab\.com)");
 regex!(pub regex_pypi r"^https?://pypi\.(python\.org|io|org)");
 regex!(pub regex_pypisrc r"^https?://pypi\.(python\.org|io|org)/packages/source/");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel(name: &str, relop: &str, version: &str) -> DebRelation {
+        DebRelation {
+            name: name.into(),
+            relop: relop.into(),
+            version: version.into(),
+        }
+    }
+
+    #[test]
+    fn deb_relation_parse() {
+        assert_eq!(DebRelation::parse("libc6 (>= 2.36)"), Some(rel("libc6", ">=", "2.36")));
+        assert_eq!(
+            DebRelation::parse(" foo(<<1:2.0-1) "),
+            Some(rel("foo", "<<", "1:2.0-1"))
+        );
+        assert_eq!(
+            DebRelation::parse("python3:any (= 3.11)"),
+            Some(rel("python3", "=", "3.11"))
+        );
+        assert_eq!(DebRelation::parse("perl:native"), Some(rel("perl", "", "")));
+        assert_eq!(DebRelation::parse("  "), None);
+
+        assert_eq!(
+            DebRelation::parse_alternatives("a (>> 1) | b:any | c (<= 2)"),
+            vec![rel("a", ">>", "1"), rel("b", "", ""), rel("c", "<=", "2")]
+        );
+        assert_eq!(
+            DebRelation::parse_field("a, b | c (= 1),, "),
+            vec![vec![rel("a", "", "")], vec![rel("b", "", ""), rel("c", "=", "1")]]
+        );
+    }
+
+    #[test]
+    fn deb_relation_matches() {
+        let cases = [
+            ("<<", [true, false, false]),
+            ("<=", [true, true, false]),
+            ("=", [false, true, false]),
+            (">=", [false, true, true]),
+            (">>", [false, false, true]),
+        ];
+        for (relop, expected) in cases {
+            let dep = rel("foo", relop, "1.2-1");
+            for (version, expected) in ["1.2~rc1-1", "1.2-1", "1:1.0"].into_iter().zip(expected) {
+                assert_eq!(dep.matches(version), expected, "{version} {relop} 1.2-1");
+            }
+        }
+
+        assert!(rel("foo", "", "").matches("0"));
+        assert!(!rel("foo", "!=", "1").matches("1"));
+    }
+}
@@ -0,0 +1,1038 @@
+//! Stable JSON API under `/api/v1`.
+//!
+//! Unlike `?type=json`, which serializes whatever the HTML template needs,
+//! the response types here are part of the public interface: fields may be
+//! added, but never renamed or removed within `v1`.
+
+use crate::db::{self, fetch_page, Paging};
+use crate::openapi;
+use crate::sql::*;
+use crate::utils::*;
+use axum::response::{IntoResponse, Response};
+use itertools::Itertools;
+use mime_guess::mime;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow};
+use time::format_description::well_known::Rfc3339;
+
+/// A source package and its builds.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Package {
+    pub name: String,
+    pub description: String,
+    pub tree: String,
+    pub branch: String,
+    pub category: String,
+    pub section: String,
+    pub directory: String,
+    /// Version without epoch and release
+    pub version: String,
+    pub full_version: String,
+    pub committer: String,
+    /// RFC 3339
+    pub commit_time: String,
+    pub noarch: bool,
+    pub fail_arch: String,
+    pub upstream: Option<Upstream>,
+    pub dependencies: Vec<Dependency>,
+    /// Packages providing the shared libraries this package links to
+    pub library_dependencies: Vec<String>,
+    pub errors: Vec<PackageError>,
+    pub debs: Vec<Deb>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Upstream {
+    /// `tarball`, `git`, `svn`, `bzr` or `pypi`
+    pub r#type: String,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Dependency {
+    /// `PKGDEP`, `BUILDDEP`, `PKGRECOM` ...
+    pub relationship: String,
+    /// Empty unless the dependency only applies to one architecture
+    pub architecture: String,
+    pub package: String,
+    /// Version constraint such as `>=1.0`, may be empty
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PackageError {
+    pub message: String,
+    pub tree: String,
+    pub branch: String,
+    pub path: String,
+    pub line: Option<i32>,
+    pub column: Option<i32>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Deb {
+    pub repo: String,
+    pub version: String,
+    pub architecture: String,
+    pub filename: String,
+    pub size: i64,
+    pub testing: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Repo {
+    /// Full name such as `amd64/stable`
+    pub name: String,
+    pub architecture: String,
+    pub branch: String,
+    pub category: String,
+    pub testing: bool,
+    pub packages: i32,
+    pub ghost: i32,
+    pub lagging: i32,
+    pub missing: i32,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RepoPackages {
+    pub repo: String,
+    pub packages: Vec<RepoPackage>,
+    pub page: Pagination,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RepoPackage {
+    pub name: String,
+    pub description: String,
+    /// Latest version in the repo
+    pub version: String,
+    /// Current version in the source tree, empty for ghost packages
+    pub source_version: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Changelog {
+    pub package: String,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Change {
+    pub version: String,
+    pub tree: String,
+    pub branch: String,
+    pub commit: String,
+    pub urgency: String,
+    pub message: String,
+    pub maintainer_name: String,
+    pub maintainer_email: String,
+    /// RFC 3339
+    pub timestamp: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Revdeps {
+    pub package: String,
+    pub dependents: Vec<Dependent>,
+    pub library_dependents: Vec<LibraryDependent>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Dependent {
+    pub package: String,
+    /// `PKGDEP`, `BUILDDEP`, `PKGRECOM` or `PKGSUG`
+    pub relationship: String,
+    pub architecture: String,
+    /// Version constraint such as `>=1.0`, may be empty
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LibraryDependent {
+    pub soname: String,
+    pub packages: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DebFiles {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub maintainer: String,
+    pub installed_size: i64,
+    pub filename: String,
+    pub size: i64,
+    pub sha256: String,
+    pub library_depends: Vec<String>,
+    pub library_provides: Vec<String>,
+    pub files: Vec<File>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct File {
+    pub path: String,
+    pub size: i64,
+    /// tar entry type, `0` for regular files, `2` for symlinks, `5` for directories
+    pub r#type: i16,
+    pub mode: i32,
+    pub uid: i64,
+    pub gid: i64,
+    pub user: String,
+    pub group: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchResults {
+    pub query: String,
+    pub packages: Vec<SearchResult>,
+    pub page: Pagination,
+}
+
+/// Position of a paged listing.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Pagination {
+    /// Current page, starting from 1, always 1 for `?cursor=` listings
+    pub cur: u32,
+    /// Last page
+    pub max: u32,
+    /// Number of items across all pages
+    pub count: u32,
+    /// Token for `?cursor=` to continue a keyset listing, absent on the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl From<db::Page> for Pagination {
+    fn from(page: db::Page) -> Self {
+        Self {
+            cur: page.cur,
+            max: page.max,
+            count: page.count,
+            next_cursor: page.next_cursor,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchResult {
+    pub name: String,
+    pub description: String,
+    pub version: String,
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Response> {
+    Ok(build_resp(mime::APPLICATION_JSON.as_ref(), serde_json::to_string(value)?).into_response())
+}
+
+fn rfc3339(timestamp: time::OffsetDateTime) -> Result<String> {
+    Ok(timestamp.format(&Rfc3339).map_err(anyhow::Error::from)?)
+}
+
+typed_path!("/api/v1/packages/:name", ApiPackage, name);
+pub async fn api_package(ApiPackage { name }: ApiPackage, db: Ext) -> Result<Response> {
+    #[derive(FromRow)]
+    struct PackageRow {
+        name: String,
+        tree: String,
+        branch: String,
+        category: String,
+        section: String,
+        directory: String,
+        description: String,
+        version: String,
+        full_version: String,
+        commit_time: time::OffsetDateTime,
+        committer: String,
+        dependency: String,
+        noarch: bool,
+        fail_arch: String,
+        srctype: String,
+        srcurl: String,
+    }
+
+    #[derive(FromRow)]
+    struct ErrorRow {
+        message: String,
+        path: String,
+        tree: String,
+        branch: String,
+        col: Option<i32>,
+        line: Option<i32>,
+    }
+
+    #[derive(FromRow)]
+    struct DebRow {
+        version: String,
+        architecture: String,
+        repo: String,
+        testing: i32,
+        filename: String,
+        size: i64,
+    }
+
+    let mut pkg: Option<PackageRow> = query_as(SQL_GET_PACKAGE_INFO)
+        .bind(&name)
+        .fetch_optional(&db.meta)
+        .await?;
+    if pkg.is_none() {
+        pkg = query_as(SQL_GET_PACKAGE_INFO_GHOST)
+            .bind(&name)
+            .fetch_optional(&db.meta)
+            .await?;
+    }
+    let pkg = if let Some(pkg) = pkg {
+        pkg
+    } else {
+        not_found!("Package \"{name}\" not found");
+    };
+
+    let errors: Vec<ErrorRow> = query_as(SQL_GET_PACKAGE_ERRORS).bind(&name).fetch_all(&db.meta).await?;
+    let debs: Vec<DebRow> = query_as(SQL_GET_PACKAGE_DPKG).bind(&name).fetch_all(&db.meta).await?;
+    let library_deps: Vec<(String,)> = query_as(SQL_GET_PACKAGE_LIBRARY_DEP)
+        .bind(&name)
+        .fetch_all(&db.pv)
+        .await?;
+
+    let dependencies = pkg
+        .dependency
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut iter = s.split('|').map(String::from);
+            let mut next = || iter.next().unwrap_or_default();
+            let (package, version, relationship, architecture) = (next(), next(), next(), next());
+            Dependency {
+                relationship,
+                architecture,
+                package,
+                version,
+            }
+        })
+        .sorted_by(|a, b| {
+            (&a.relationship, &a.architecture, &a.package).cmp(&(&b.relationship, &b.architecture, &b.package))
+        })
+        .collect();
+
+    let res = Package {
+        upstream: Src::parse(&pkg.srctype, &pkg.srcurl).map(|src| Upstream {
+            r#type: match src.srctype {
+                SrcType::Tarball => "tarball",
+                SrcType::Git => "git",
+                SrcType::SvnSrc => "svn",
+                SrcType::BzrSrc => "bzr",
+                SrcType::Pypi => "pypi",
+            }
+            .into(),
+            url: src.srcurl,
+        }),
+        dependencies,
+        library_dependencies: library_deps.into_iter().map(|dep| dep.0).collect(),
+        errors: errors
+            .into_iter()
+            .map(|e| PackageError {
+                message: e.message,
+                tree: e.tree,
+                branch: e.branch,
+                path: e.path,
+                line: e.line,
+                column: e.col,
+            })
+            .collect(),
+        debs: debs
+            .into_iter()
+            .map(|d| Deb {
+                repo: d.repo,
+                version: d.version,
+                architecture: d.architecture,
+                filename: d.filename,
+                size: d.size,
+                testing: d.testing != 0,
+            })
+            .collect(),
+        commit_time: rfc3339(pkg.commit_time)?,
+        name: pkg.name,
+        description: pkg.description,
+        tree: pkg.tree,
+        branch: pkg.branch,
+        category: pkg.category,
+        section: pkg.section,
+        directory: pkg.directory,
+        version: pkg.version,
+        full_version: pkg.full_version,
+        committer: pkg.committer,
+        noarch: pkg.noarch,
+        fail_arch: pkg.fail_arch,
+    };
+
+    json(&res)
+}
+
+typed_path!("/api/v1/repos", ApiRepos);
+pub async fn api_repos(_: ApiRepos, db: Ext) -> Result<Response> {
+    let res = db_repos(&db)
+        .await?
+        .into_values()
+        .map(|repo| Repo {
+            name: repo.name,
+            architecture: repo.architecture,
+            branch: repo.branch,
+            category: repo.category,
+            testing: repo.testing != 0,
+            packages: repo.pkgcount,
+            ghost: repo.ghost,
+            lagging: repo.lagging,
+            missing: repo.missing,
+        })
+        .collect_vec();
+
+    json(&res)
+}
+
+typed_path!("/api/v1/repos/*repo", ApiRepo, repo);
+pub async fn api_repo(ApiRepo { repo }: ApiRepo, q: Query, db: Ext) -> Result<Response> {
+    #[derive(FromRow)]
+    struct PackageRow {
+        name: String,
+        full_version: String,
+        dpkg_version: String,
+        description: String,
+    }
+
+    let repo = strip_prefix(&repo);
+    get_repo(repo, &db).await?;
+
+    let (packages, page): (Vec<PackageRow>, _) =
+        fetch_page(&db.meta, q.get_page()?, || query_as(SQL_GET_PACKAGE_REPO).bind(repo)).await?;
+
+    let res = RepoPackages {
+        repo: repo.into(),
+        packages: packages
+            .into_iter()
+            .map(|pkg| RepoPackage {
+                name: pkg.name,
+                description: pkg.description,
+                version: pkg.dpkg_version,
+                source_version: pkg.full_version,
+            })
+            .collect(),
+        page: page.into(),
+    };
+
+    json(&res)
+}
+
+typed_path!("/api/v1/changelog/:name", ApiChangelog, name);
+pub async fn api_changelog(ApiChangelog { name }: ApiChangelog, db: Ext) -> Result<Response> {
+    #[derive(FromRow)]
+    struct ChangeRow {
+        githash: String,
+        version: String,
+        tree: String,
+        branch: String,
+        urgency: String,
+        message: String,
+        maintainer_name: String,
+        maintainer_email: String,
+        timestamp: time::OffsetDateTime,
+    }
+
+    let changes: Vec<ChangeRow> = query_as(SQL_GET_PACKAGE_CHANGELOG)
+        .bind(&name)
+        .fetch_all(&db.meta)
+        .await?;
+
+    if changes.is_empty() {
+        not_found!("Package \"{name}\" not found.");
+    }
+
+    let res = Changelog {
+        changes: changes
+            .into_iter()
+            .map(|ch| {
+                Ok(Change {
+                    timestamp: rfc3339(ch.timestamp)?,
+                    version: ch.version,
+                    tree: ch.tree,
+                    branch: ch.branch,
+                    commit: ch.githash,
+                    urgency: ch.urgency,
+                    message: ch.message,
+                    maintainer_name: ch.maintainer_name,
+                    maintainer_email: ch.maintainer_email,
+                })
+            })
+            .collect::<Result<_>>()?,
+        package: name,
+    };
+
+    json(&res)
+}
+
+typed_path!("/api/v1/revdep/:name", ApiRevdep, name);
+pub async fn api_revdep(ApiRevdep { name }: ApiRevdep, db: Ext) -> Result<Response> {
+    #[derive(FromRow)]
+    struct RevDepRow {
+        package: String,
+        version: String,
+        relationship: String,
+        architecture: String,
+    }
+
+    let res = query(SQL_PACKAGE_EXISTS).bind(&name).fetch_optional(&db.meta).await?;
+    if res.is_none() {
+        not_found!("Package \"{name}\" not found.");
+    }
+
+    let deps: Vec<RevDepRow> = query_as(SQL_GET_PACKAGE_REV_REL)
+        .bind(&name)
+        .fetch_all(&db.meta)
+        .await?;
+    let sorevdeps: Vec<(String, String)> = query_as(SQL_GET_PACKAGE_SO_REVDEPS)
+        .bind(&name)
+        .fetch_all(&db.pv)
+        .await?;
+
+    let res = Revdeps {
+        dependents: deps
+            .into_iter()
+            .map(|dep| Dependent {
+                package: dep.package,
+                relationship: dep.relationship,
+                architecture: dep.architecture,
+                version: dep.version,
+            })
+            .collect(),
+        library_dependents: sorevdeps
+            .into_iter()
+            .into_group_map()
+            .into_iter()
+            .sorted()
+            .map(|(soname, packages)| LibraryDependent {
+                soname,
+                packages: packages.into_iter().sorted().collect(),
+            })
+            .collect(),
+        package: name,
+    };
+
+    json(&res)
+}
+
+typed_path!(
+    "/api/v1/files/:reponame/:branch/:name/:version",
+    ApiFiles,
+    reponame,
+    branch,
+    name,
+    version
+);
+pub async fn api_files(
+    ApiFiles {
+        reponame,
+        branch,
+        name,
+        version,
+    }: ApiFiles,
+    db: Ext,
+) -> Result<Response> {
+    #[derive(FromRow)]
+    struct DebRow {
+        package: String,
+        version: String,
+        architecture: String,
+        repo: String,
+        maintainer: String,
+        installed_size: i64,
+        filename: String,
+        size: i64,
+        sha256: String,
+    }
+
+    #[derive(FromRow)]
+    struct FileRow {
+        filename: Option<String>,
+        size: i64,
+        ftype: i16,
+        perm: i32,
+        uid: i64,
+        gid: i64,
+        uname: String,
+        gname: String,
+    }
+
+    let repo = format!("{reponame}/{branch}");
+
+    let deb: Option<DebRow> = query_as(SQL_GET_PACKAGE_DEB_LOCAL)
+        .bind(&name)
+        .bind(&version)
+        .bind(&repo)
+        .fetch_optional(&db.meta)
+        .await?;
+    let deb = if let Some(deb) = deb {
+        deb
+    } else {
+        not_found!("Package \"{name}\" ({version}) not found in {repo}");
+    };
+
+    let files: Vec<FileRow> = query_as(SQL_GET_PACKAGE_DEB_FILES)
+        .bind(&name)
+        .bind(&version)
+        .bind(&repo)
+        .fetch_all(&db.pv)
+        .await?;
+    let sodeps: Vec<(i32, Option<String>)> = query_as(SQL_GET_PACKAGE_SODEP)
+        .bind(&name)
+        .bind(&version)
+        .bind(&repo)
+        .fetch_all(&db.pv)
+        .await?;
+
+    let (library_depends, library_provides) = sodeps
+        .into_iter()
+        .filter_map(|(depends, soname)| Some((depends, soname?)))
+        .partition_map(|(depends, soname)| {
+            if depends != 0 {
+                itertools::Either::Left(soname)
+            } else {
+                itertools::Either::Right(soname)
+            }
+        });
+
+    let res = DebFiles {
+        package: deb.package,
+        version: deb.version,
+        architecture: deb.architecture,
+        repo: deb.repo,
+        maintainer: deb.maintainer,
+        installed_size: deb.installed_size,
+        filename: deb.filename,
+        size: deb.size,
+        sha256: deb.sha256,
+        library_depends,
+        library_provides,
+        files: files
+            .into_iter()
+            .map(|f| File {
+                path: f.filename.unwrap_or_default(),
+                size: f.size,
+                r#type: f.ftype,
+                mode: f.perm,
+                uid: f.uid,
+                gid: f.gid,
+                user: f.uname,
+                group: f.gname,
+            })
+            .collect(),
+    };
+
+    json(&res)
+}
+
+typed_path!("/api/v1/search", ApiSearch);
+pub async fn api_search(_: ApiSearch, q: Query, db: Ext) -> Result<Response> {
+    #[derive(FromRow)]
+    struct PackageRow {
+        name: String,
+        description: String,
+        full_version: String,
+    }
+
+    let query = q.get_query().as_deref().map(str::trim).unwrap_or_default();
+    if query.is_empty() {
+        return Err(Error::NotSupported("missing search keywords in ?q=".into()));
+    }
+
+    let paging = q.get_page()?;
+    if let Paging::After(_) = paging {
+        return Err(Error::NotSupported(
+            "search results are ranked and cannot be paged by cursor".into(),
+        ));
+    }
+
+    let (packages, page): (Vec<PackageRow>, _) = fetch_page(&db.meta, paging, || {
+        query_as(SQL_SEARCH_PACKAGES_DESC)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+            .bind(query)
+    })
+    .await?;
+
+    let res = SearchResults {
+        query: query.into(),
+        packages: packages
+            .into_iter()
+            .map(|pkg| SearchResult {
+                name: pkg.name,
+                description: pkg.description,
+                version: pkg.full_version,
+            })
+            .collect(),
+        page: page.into(),
+    };
+
+    json(&res)
+}
+
+impl openapi::Route<Package> for ApiPackage {
+    const SUMMARY: &'static str = "Package details";
+}
+
+impl openapi::Route<Vec<Repo>> for ApiRepos {
+    const SUMMARY: &'static str = "All repositories";
+}
+
+impl openapi::Route<RepoPackages> for ApiRepo {
+    const SUMMARY: &'static str = "Packages in a repository";
+    const PARAMS: &'static [&'static str] = &["page", "cursor"];
+}
+
+impl openapi::Route<Changelog> for ApiChangelog {
+    const SUMMARY: &'static str = "Changelog of a package";
+}
+
+impl openapi::Route<Revdeps> for ApiRevdep {
+    const SUMMARY: &'static str = "Reverse dependencies of a package";
+}
+
+impl openapi::Route<DebFiles> for ApiFiles {
+    const SUMMARY: &'static str = "Files in a deb";
+}
+
+impl openapi::Route<SearchResults> for ApiSearch {
+    const SUMMARY: &'static str = "Search packages";
+    const PARAMS: &'static [&'static str] = &["q", "page"];
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<ApiPackage, _>()
+        .route::<ApiRepos, _>()
+        .route::<ApiRepo, _>()
+        .route::<ApiChangelog, _>()
+        .route::<ApiRevdep, _>()
+        .route::<ApiFiles, _>()
+        .route::<ApiSearch, _>()
+}
+
+/// Pin the serialized form of every response type, a failure here means
+/// clients of `/api/v1` would break.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn page() -> Pagination {
+        Pagination {
+            cur: 1,
+            max: 3,
+            count: 120,
+            next_cursor: None,
+        }
+    }
+
+    #[test]
+    fn package() {
+        let pkg = Package {
+            name: "bash".into(),
+            description: "GNU Bourne Again shell".into(),
+            tree: "aosc-os-abbs".into(),
+            branch: "stable".into(),
+            category: "app".into(),
+            section: "shells".into(),
+            directory: "bash".into(),
+            version: "5.2.21".into(),
+            full_version: "5.2.21-1".into(),
+            committer: "Someone <someone@aosc.io>".into(),
+            commit_time: "2024-01-02T03:04:05Z".into(),
+            noarch: false,
+            fail_arch: "".into(),
+            upstream: Some(Upstream {
+                r#type: "tarball".into(),
+                url: "https://ftp.gnu.org/gnu/bash/bash-5.2.21.tar.gz".into(),
+            }),
+            dependencies: vec![Dependency {
+                relationship: "PKGDEP".into(),
+                architecture: "".into(),
+                package: "readline".into(),
+                version: ">=8.2".into(),
+            }],
+            library_dependencies: vec!["glibc".into()],
+            errors: vec![PackageError {
+                message: "unknown variable".into(),
+                tree: "aosc-os-abbs".into(),
+                branch: "stable".into(),
+                path: "app-shells/bash/spec".into(),
+                line: Some(3),
+                column: None,
+            }],
+            debs: vec![Deb {
+                repo: "amd64/stable".into(),
+                version: "5.2.21-1".into(),
+                architecture: "amd64".into(),
+                filename: "pool/stable/main/b/bash_5.2.21-1_amd64.deb".into(),
+                size: 1024,
+                testing: false,
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(pkg).unwrap(),
+            json!({
+                "name": "bash",
+                "description": "GNU Bourne Again shell",
+                "tree": "aosc-os-abbs",
+                "branch": "stable",
+                "category": "app",
+                "section": "shells",
+                "directory": "bash",
+                "version": "5.2.21",
+                "full_version": "5.2.21-1",
+                "committer": "Someone <someone@aosc.io>",
+                "commit_time": "2024-01-02T03:04:05Z",
+                "noarch": false,
+                "fail_arch": "",
+                "upstream": {
+                    "type": "tarball",
+                    "url": "https://ftp.gnu.org/gnu/bash/bash-5.2.21.tar.gz",
+                },
+                "dependencies": [
+                    { "relationship": "PKGDEP", "architecture": "", "package": "readline", "version": ">=8.2" },
+                ],
+                "library_dependencies": ["glibc"],
+                "errors": [{
+                    "message": "unknown variable",
+                    "tree": "aosc-os-abbs",
+                    "branch": "stable",
+                    "path": "app-shells/bash/spec",
+                    "line": 3,
+                    "column": null,
+                }],
+                "debs": [{
+                    "repo": "amd64/stable",
+                    "version": "5.2.21-1",
+                    "architecture": "amd64",
+                    "filename": "pool/stable/main/b/bash_5.2.21-1_amd64.deb",
+                    "size": 1024,
+                    "testing": false,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn repos() {
+        let repo = Repo {
+            name: "amd64/stable".into(),
+            architecture: "amd64".into(),
+            branch: "stable".into(),
+            category: "base".into(),
+            testing: false,
+            packages: 100,
+            ghost: 1,
+            lagging: 2,
+            missing: 3,
+        };
+
+        assert_eq!(
+            serde_json::to_value(vec![repo]).unwrap(),
+            json!([{
+                "name": "amd64/stable",
+                "architecture": "amd64",
+                "branch": "stable",
+                "category": "base",
+                "testing": false,
+                "packages": 100,
+                "ghost": 1,
+                "lagging": 2,
+                "missing": 3,
+            }])
+        );
+    }
+
+    #[test]
+    fn repo_packages() {
+        let res = RepoPackages {
+            repo: "amd64/stable".into(),
+            packages: vec![RepoPackage {
+                name: "bash".into(),
+                description: "GNU Bourne Again shell".into(),
+                version: "5.2.15".into(),
+                source_version: "5.2.21-1".into(),
+            }],
+            page: Pagination {
+                next_cursor: Some("62617368".into()),
+                ..page()
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            json!({
+                "repo": "amd64/stable",
+                "packages": [{
+                    "name": "bash",
+                    "description": "GNU Bourne Again shell",
+                    "version": "5.2.15",
+                    "source_version": "5.2.21-1",
+                }],
+                "page": { "cur": 1, "max": 3, "count": 120, "next_cursor": "62617368" },
+            })
+        );
+    }
+
+    #[test]
+    fn changelog() {
+        let res = Changelog {
+            package: "bash".into(),
+            changes: vec![Change {
+                version: "5.2.21-1".into(),
+                tree: "aosc-os-abbs".into(),
+                branch: "stable".into(),
+                commit: "0123abcd".into(),
+                urgency: "medium".into(),
+                message: "bash: update to 5.2.21".into(),
+                maintainer_name: "Someone".into(),
+                maintainer_email: "someone@aosc.io".into(),
+                timestamp: "2024-01-02T03:04:05Z".into(),
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            json!({
+                "package": "bash",
+                "changes": [{
+                    "version": "5.2.21-1",
+                    "tree": "aosc-os-abbs",
+                    "branch": "stable",
+                    "commit": "0123abcd",
+                    "urgency": "medium",
+                    "message": "bash: update to 5.2.21",
+                    "maintainer_name": "Someone",
+                    "maintainer_email": "someone@aosc.io",
+                    "timestamp": "2024-01-02T03:04:05Z",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn revdeps() {
+        let res = Revdeps {
+            package: "readline".into(),
+            dependents: vec![Dependent {
+                package: "bash".into(),
+                relationship: "PKGDEP".into(),
+                architecture: "".into(),
+                version: ">=8.2".into(),
+            }],
+            library_dependents: vec![LibraryDependent {
+                soname: "libreadline.so.8".into(),
+                packages: vec!["bash".into(), "python-3".into()],
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            json!({
+                "package": "readline",
+                "dependents": [
+                    { "package": "bash", "relationship": "PKGDEP", "architecture": "", "version": ">=8.2" },
+                ],
+                "library_dependents": [
+                    { "soname": "libreadline.so.8", "packages": ["bash", "python-3"] },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn deb_files() {
+        let res = DebFiles {
+            package: "bash".into(),
+            version: "5.2.21-1".into(),
+            architecture: "amd64".into(),
+            repo: "amd64/stable".into(),
+            maintainer: "Someone <someone@aosc.io>".into(),
+            installed_size: 4096,
+            filename: "pool/stable/main/b/bash_5.2.21-1_amd64.deb".into(),
+            size: 1024,
+            sha256: "00".into(),
+            library_depends: vec!["libc.so.6".into()],
+            library_provides: vec![],
+            files: vec![File {
+                path: "/usr/bin/bash".into(),
+                size: 2048,
+                r#type: 0,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                user: "root".into(),
+                group: "root".into(),
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            json!({
+                "package": "bash",
+                "version": "5.2.21-1",
+                "architecture": "amd64",
+                "repo": "amd64/stable",
+                "maintainer": "Someone <someone@aosc.io>",
+                "installed_size": 4096,
+                "filename": "pool/stable/main/b/bash_5.2.21-1_amd64.deb",
+                "size": 1024,
+                "sha256": "00",
+                "library_depends": ["libc.so.6"],
+                "library_provides": [],
+                "files": [{
+                    "path": "/usr/bin/bash",
+                    "size": 2048,
+                    "type": 0,
+                    "mode": 493,
+                    "uid": 0,
+                    "gid": 0,
+                    "user": "root",
+                    "group": "root",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn search_results() {
+        let res = SearchResults {
+            query: "shell".into(),
+            packages: vec![SearchResult {
+                name: "bash".into(),
+                description: "GNU Bourne Again shell".into(),
+                version: "5.2.21-1".into(),
+            }],
+            page: page(),
+        };
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            json!({
+                "query": "shell",
+                "packages": [
+                    { "name": "bash", "description": "GNU Bourne Again shell", "version": "5.2.21-1" },
+                ],
+                "page": { "cur": 1, "max": 3, "count": 120 },
+            })
+        );
+    }
+
+    #[test]
+    fn timestamp() {
+        let t = time::OffsetDateTime::from_unix_timestamp(1704164645).unwrap();
+        assert_eq!(rfc3339(t).unwrap(), "2024-01-02T03:04:05Z");
+    }
+}
@@ -0,0 +1,475 @@
+use crate::filters;
+use crate::openapi;
+use crate::sql::*;
+use crate::utils::*;
+use askama::Template;
+use axum::response::IntoResponse;
+use indexmap::{IndexMap, IndexSet};
+use itertools::Itertools;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{query, query_as, query_scalar, FromRow};
+use std::collections::{HashMap, HashSet};
+
+const GRAPH_MAX_DEPTH: u32 = 5;
+const GRAPH_MAX_NODES: usize = 1000;
+
+#[derive(Debug, FromRow, Serialize, Clone, PartialEq, Eq, Hash, JsonSchema)]
+struct Edge {
+    package: String,
+    dependency: String,
+    relationship: String,
+    architecture: String,
+    version: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct Node {
+    name: String,
+    depth: u32,
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn to_dot(name: &str, nodes: &[Node], edges: &[Edge], relationships: &[String]) -> String {
+    let mut dot = format!("digraph {} {{\n    rankdir=\"LR\";\n", dot_quote(name));
+
+    for node in nodes {
+        let style = if node.depth == 0 {
+            " [style=\"filled\", fillcolor=\"lightblue\"]"
+        } else {
+            ""
+        };
+        dot.push_str(&format!("    {}{style};\n", dot_quote(&node.name)));
+    }
+
+    // only label edges when their relationship is ambiguous
+    let label = relationships.len() > 1;
+    for edge in edges {
+        dot.push_str(&format!(
+            "    {} -> {}",
+            dot_quote(&edge.package),
+            dot_quote(&edge.dependency)
+        ));
+        if label {
+            let mut text = edge.relationship.clone();
+            if !edge.architecture.is_empty() {
+                text.push_str(&format!(" [{}]", edge.architecture));
+            }
+            dot.push_str(&format!(" [label={}]", dot_quote(&text)));
+        }
+        dot.push_str(";\n");
+    }
+    dot.push('}');
+
+    dot
+}
+
+fn dot_to_svg(dot: &str) -> Result<String> {
+    use layout::backends::svg::SVGWriter;
+    use layout::gv::{DotParser, GraphBuilder};
+
+    let graph = DotParser::new(dot).process().map_err(|e| anyhow::anyhow!(e))?;
+    let mut builder = GraphBuilder::new();
+    builder.visit_graph(&graph);
+
+    let mut svg = SVGWriter::new();
+    builder.get().do_it(false, false, false, &mut svg);
+
+    Ok(svg.finalize())
+}
+
+typed_path!("/graph/:name", Graph, name);
+pub async fn graph(Graph { name }: Graph, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "graph.html")]
+    struct Template<'a> {
+        name: &'a str,
+        depth: u32,
+        direction: &'a str,
+        relationships: &'a Vec<String>,
+        truncated: bool,
+        nodes: &'a Vec<Node>,
+        edges: &'a Vec<Edge>,
+        #[serde(skip)]
+        svg: String,
+    }
+
+    impl openapi::Route<Template<'static>> for Graph {
+        const SUMMARY: &'static str = "Dependency graph around a package";
+        const PARAMS: &'static [&'static str] = &["depth", "direction", "rel", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "graph.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        edges: &'a Vec<Edge>,
+    }
+
+    let res = query(SQL_PACKAGE_EXISTS).bind(&name).fetch_optional(&db.meta).await?;
+    if res.is_none() {
+        not_found!("Package \"{name}\" not found.");
+    }
+
+    let depth = q.get_depth().unwrap_or(1).clamp(1, GRAPH_MAX_DEPTH);
+    let direction = match q.get_direction() {
+        None => "forward",
+        Some(d @ ("forward" | "reverse" | "both")) => d,
+        Some(d) => return Err(Error::NotSupported(format!("unknown direction \"{d}\""))),
+    };
+    let relationships = &q.get_rel().unwrap_or_else(|| vec!["PKGDEP".into()]);
+    if let Some(rel) = relationships.iter().find(|r| !DEP_REL.iter().any(|(k, _)| k == r)) {
+        return Err(Error::NotSupported(format!("unknown relationship \"{rel}\"")));
+    }
+
+    // breadth first walk, one query per level
+    let mut seen: IndexMap<String, u32> = IndexMap::from([(name.clone(), 0)]);
+    let mut edges: IndexSet<Edge> = IndexSet::new();
+    let mut frontier = vec![name.clone()];
+    let mut truncated = false;
+
+    for level in 1..=depth {
+        let forward = if direction != "reverse" {
+            frontier.clone()
+        } else {
+            vec![]
+        };
+        let reverse = if direction != "forward" {
+            frontier.clone()
+        } else {
+            vec![]
+        };
+
+        let found: Vec<Edge> = query_as(SQL_GET_DEPENDENCY_EDGES)
+            .bind(&forward)
+            .bind(&reverse)
+            .bind(relationships)
+            .fetch_all(&db.meta)
+            .await?;
+
+        // alternatives such as `a | b` become one edge each
+        let found = found.into_iter().flat_map(|edge| {
+            DebRelation::parse_alternatives(&edge.dependency)
+                .into_iter()
+                .map(move |alt| Edge {
+                    dependency: alt.name,
+                    version: if alt.relop.is_empty() {
+                        edge.version.clone()
+                    } else {
+                        alt.relop + &alt.version
+                    },
+                    ..edge.clone()
+                })
+        });
+
+        frontier = vec![];
+        for edge in found {
+            let next = if forward.contains(&edge.package) {
+                &edge.dependency
+            } else {
+                &edge.package
+            };
+            if !seen.contains_key(next) {
+                if seen.len() >= GRAPH_MAX_NODES {
+                    truncated = true;
+                    continue;
+                }
+                seen.insert(next.clone(), level);
+                frontier.push(next.clone());
+            }
+            if seen.contains_key(&edge.package) && seen.contains_key(&edge.dependency) {
+                edges.insert(edge);
+            }
+        }
+
+        if frontier.is_empty() {
+            break;
+        }
+    }
+
+    let nodes = &seen.into_iter().map(|(name, depth)| Node { name, depth }).collect_vec();
+    let edges = &edges.into_iter().collect_vec();
+    let dot = to_dot(&name, nodes, edges, relationships);
+
+    match q.get_type() {
+        Some("dot") => return build_resp("text/vnd.graphviz; charset=utf-8", dot).map(IntoResponse::into_response),
+        Some("json") | Some("tsv") => {
+            let ctx = Template {
+                name: &name,
+                depth,
+                direction,
+                relationships,
+                truncated,
+                nodes,
+                edges,
+                svg: String::new(),
+            };
+            return render(ctx, Some(TemplateTsv { edges }), &q);
+        }
+        _ => (),
+    }
+
+    // laying out large graphs takes a while
+    let svg = tokio::task::spawn_blocking(move || dot_to_svg(&dot))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))??;
+
+    if q.get_type() == Some("svg") {
+        return build_resp(mime_guess::mime::IMAGE_SVG.as_ref(), svg).map(IntoResponse::into_response);
+    }
+
+    let ctx = Template {
+        name: &name,
+        depth,
+        direction,
+        relationships,
+        truncated,
+        nodes,
+        edges,
+        svg,
+    };
+
+    render::<_, Template>(ctx, None, &q)
+}
+
+/// Runtime dependencies gathered while walking a closure, shared by all
+/// architectures.
+#[derive(Default)]
+struct DepIndex {
+    known: HashSet<String>,
+    /// package -> (dependency, architecture)
+    depends: HashMap<String, Vec<(String, String)>>,
+    /// provided name -> (provider, architecture)
+    provides: HashMap<String, Vec<(String, String)>>,
+}
+
+impl DepIndex {
+    fn depends<'a>(&'a self, package: &str, arch: &'a str) -> impl Iterator<Item = &'a str> {
+        let rows = self.depends.get(package).map(Vec::as_slice).unwrap_or_default();
+        // architecture specific PKGDEP__ARCH replaces the generic PKGDEP
+        let target = if rows.iter().any(|(_, a)| a == arch) { arch } else { "" };
+        rows.iter().filter(move |(_, a)| a == target).map(|(d, _)| d.as_str())
+    }
+
+    /// Pick the first alternative that is a package or has a provider.
+    fn resolve(&self, dependency: &str, arch: &str) -> Option<String> {
+        DebRelation::parse_alternatives(dependency).into_iter().find_map(|alt| {
+            if self.known.contains(&alt.name) {
+                return Some(alt.name);
+            }
+            self.provides
+                .get(&alt.name)?
+                .iter()
+                .find(|(_, a)| a.is_empty() || a == arch)
+                .map(|(p, _)| p.clone())
+        })
+    }
+}
+
+typed_path!("/closure/:name", Closure, name);
+pub async fn closure(Closure { name }: Closure, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow)]
+    struct Row {
+        package: String,
+        dependency: String,
+        relationship: String,
+        architecture: String,
+    }
+
+    #[derive(Debug, FromRow)]
+    struct Size {
+        package: String,
+        installed_size: i64,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct Member {
+        package: String,
+        depth: u32,
+        required_by: String,
+        installed_size: Option<i64>,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct ArchClosure {
+        architecture: String,
+        total_size: i64,
+        packages: Vec<Member>,
+        unresolved: Vec<String>,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "closure.html")]
+    struct Template<'a> {
+        name: &'a str,
+        closures: &'a Vec<ArchClosure>,
+    }
+
+    impl openapi::Route<Template<'static>> for Closure {
+        const SUMMARY: &'static str = "Runtime dependency closure of a package";
+        const PARAMS: &'static [&'static str] = &["arch", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "closure.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        closures: &'a Vec<ArchClosure>,
+    }
+
+    let res = query(SQL_PACKAGE_EXISTS).bind(&name).fetch_optional(&db.meta).await?;
+    if res.is_none() {
+        not_found!("Package \"{name}\" not found.");
+    }
+
+    let archs: Vec<String> = if let Some(arch) = q.get_arch() {
+        vec![arch.to_string()]
+    } else {
+        let archs: Vec<String> = query_scalar(SQL_GET_CLOSURE_ARCHS)
+            .bind(&name)
+            .fetch_all(&db.meta)
+            .await?;
+        if archs.is_empty() {
+            // noarch or not yet built, show every architecture
+            query_scalar(SQL_GET_CLOSURE_ARCHS).bind("").fetch_all(&db.meta).await?
+        } else {
+            archs
+        }
+    };
+
+    // walk the union of all architectures and alternatives, one query per level
+    let mut index = DepIndex::default();
+    let mut fetched = HashSet::new();
+    let mut frontier = vec![name.clone()];
+    while !frontier.is_empty() {
+        let names = frontier
+            .iter()
+            .flat_map(|d| DebRelation::parse_alternatives(d))
+            .map(|alt| alt.name)
+            .filter(|n| fetched.insert(n.clone()))
+            .collect_vec();
+        if names.is_empty() {
+            break;
+        }
+
+        let known: Vec<String> = query_scalar(SQL_GET_KNOWN_PACKAGES)
+            .bind(&names)
+            .fetch_all(&db.meta)
+            .await?;
+        index.known.extend(known);
+
+        let rows: Vec<Row> = query_as(SQL_GET_CLOSURE_LEVEL)
+            .bind(&names)
+            .bind(&names)
+            .fetch_all(&db.meta)
+            .await?;
+
+        frontier = vec![];
+        for row in rows {
+            if row.relationship == "PKGPROV" {
+                frontier.push(row.package.clone());
+                index
+                    .provides
+                    .entry(row.dependency)
+                    .or_default()
+                    .push((row.package, row.architecture));
+            } else {
+                frontier.push(row.dependency.clone());
+                index
+                    .depends
+                    .entry(row.package)
+                    .or_default()
+                    .push((row.dependency, row.architecture));
+            }
+        }
+    }
+
+    let mut closures = vec![];
+    for arch in archs {
+        let mut members = IndexMap::from([(
+            name.clone(),
+            Member {
+                package: name.clone(),
+                depth: 0,
+                required_by: String::new(),
+                installed_size: None,
+            },
+        )]);
+        let mut unresolved = IndexSet::new();
+
+        let mut i = 0;
+        while let Some((package, member)) = members.get_index(i) {
+            let (package, depth) = (package.clone(), member.depth);
+            for dep in index.depends(&package, &arch) {
+                match index.resolve(dep, &arch) {
+                    Some(p) if !members.contains_key(&p) => {
+                        let member = Member {
+                            package: p.clone(),
+                            depth: depth + 1,
+                            required_by: package.clone(),
+                            installed_size: None,
+                        };
+                        members.insert(p, member);
+                    }
+                    Some(_) => (),
+                    None => {
+                        unresolved.insert(dep.to_string());
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        let packages = members.keys().cloned().collect_vec();
+        let sizes: HashMap<String, i64> = query_as(SQL_GET_CLOSURE_SIZES)
+            .bind(&packages)
+            .bind(&arch)
+            .fetch_all(&db.meta)
+            .await?
+            .into_iter()
+            .map(|s: Size| (s.package, s.installed_size))
+            .collect();
+
+        let mut packages = members.into_values().collect_vec();
+        for member in packages.iter_mut() {
+            member.installed_size = sizes.get(&member.package).copied();
+        }
+
+        closures.push(ArchClosure {
+            architecture: arch,
+            total_size: sizes.values().sum(),
+            packages,
+            unresolved: unresolved.into_iter().collect(),
+        });
+    }
+
+    let closures = &closures;
+    let ctx = Template { name: &name, closures };
+    let ctx_tsv = TemplateTsv { closures };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<Graph, _>().route::<Closure, _>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_versioned_alternatives() {
+        let mut index = DepIndex::default();
+        index.known.insert("b".into());
+        index
+            .provides
+            .insert("c".into(), vec![("c-impl".into(), "arm64".into())]);
+
+        assert_eq!(index.resolve("a (>= 1) | b (<< 2)", "amd64").as_deref(), Some("b"));
+        assert_eq!(index.resolve("c (= 1) | b", "arm64").as_deref(), Some("c-impl"));
+        assert_eq!(index.resolve("c (= 1) | b", "amd64").as_deref(), Some("b"));
+        assert_eq!(index.resolve("a (>= 1)", "amd64"), None);
+    }
+}
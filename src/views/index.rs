@@ -1,25 +1,32 @@
+use crate::config;
+use crate::feed;
 use crate::filters;
+use crate::openapi;
 use crate::sql::*;
 use crate::utils::*;
 use askama::Template;
+use axum::http::Uri;
 use axum::response::IntoResponse;
+use axum::Extension;
 use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::Serialize;
 use sqlx::{query_as, FromRow};
 
 typed_path!("/", Index);
 pub async fn index(_: Index, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Serialize)]
+    #[derive(FromRow, Serialize, JsonSchema)]
     struct Package {
         name: String,
         description: String,
         full_version: String,
+        #[schemars(with = "crate::openapi::Timestamp")]
         commit_time: time::OffsetDateTime,
         ver_compare: i32,
         status: i32,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "index.html")]
     struct Template {
         total: i64,
@@ -27,8 +34,17 @@ pub async fn index(_: Index, q: Query, db: Ext) -> Result<impl IntoResponse> {
         updates: Vec<Package>,
     }
 
+    impl openapi::Route<Template> for Index {
+        const SUMMARY: &'static str = "Repositories and latest source updates";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
     let source_trees = db_trees(&db).await?;
-    let repos = db_repos(&db).await?;
+    let mut repos = db_repos(&db).await?;
+    let broken = db_broken_counts(&db).await?;
+    for repo in repos.values_mut() {
+        repo.broken = broken.get(&repo.name).copied().unwrap_or_default();
+    }
 
     let repo_categories = REPO_CAT
         .iter()
@@ -55,36 +71,82 @@ pub async fn index(_: Index, q: Query, db: Ext) -> Result<impl IntoResponse> {
 }
 
 typed_path!("/updates", Updates);
-pub async fn updates(_: Updates, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Serialize)]
+pub async fn updates(
+    _: Updates,
+    q: Query,
+    db: Ext,
+    uri: Uri,
+    Extension(global): Extension<config::Global>,
+) -> Result<impl IntoResponse> {
+    #[derive(FromRow, Serialize, JsonSchema)]
     struct Package {
         name: String,
         dpkg_version: String,
         description: String,
         full_version: String,
+        #[schemars(with = "crate::openapi::Timestamp")]
         commit_time: time::OffsetDateTime,
         ver_compare: i32,
         status: i32,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "updates.html")]
     struct Template<'a> {
         packages: &'a Vec<Package>,
     }
 
+    impl openapi::Route<Template<'static>> for Updates {
+        const SUMMARY: &'static str = "Latest source updates";
+        const PARAMS: &'static [&'static str] = &["tree", "section", "type"];
+    }
+
     #[derive(Template)]
     #[template(path = "updates.tsv", escape = "none")]
     struct TemplateTsv<'a> {
         packages: &'a Vec<Package>,
     }
 
-    let packages: &Vec<Package> = &query_as(SQL_GET_PACKAGE_NEW_LIST).bind(100).fetch_all(&db.meta).await?;
+    let packages: &Vec<Package> = &query_as(SQL_GET_PACKAGE_NEW_LIST)
+        .bind(100)
+        .bind(q.get_tree())
+        .bind(q.get_section())
+        .fetch_all(&db.meta)
+        .await?;
 
     if packages.is_empty() {
         not_found!("There's no updates.");
     }
 
+    if let Some(format) = feed::Format::from_type(q.get_type()) {
+        let base_url = global.base_url.trim_end_matches('/');
+        let title = match (q.get_tree(), q.get_section()) {
+            (None, None) => "AOSC OS package updates".to_string(),
+            (tree, section) => format!(
+                "AOSC OS package updates in {}",
+                tree.into_iter().chain(section).join("/")
+            ),
+        };
+
+        let feed = feed::Feed {
+            title,
+            link: format!("{base_url}/updates"),
+            self_link: format!("{base_url}{uri}"),
+            entries: packages
+                .iter()
+                .map(|pkg| feed::Entry {
+                    title: format!("{} {}", pkg.name, pkg.full_version),
+                    link: format!("{base_url}/packages/{}#{}", pkg.name, pkg.full_version),
+                    author: String::new(),
+                    summary: pkg.description.clone(),
+                    updated: pkg.commit_time,
+                })
+                .collect(),
+        };
+
+        return Ok(feed.render(format));
+    }
+
     let ctx = Template { packages };
     let ctx_tsv = TemplateTsv { packages };
 
@@ -99,3 +161,12 @@ pub async fn license(_: License) -> Result<impl IntoResponse> {
 
     Ok(into_response(&Template {}, None))
 }
+
+impl openapi::Route<()> for License {
+    const SUMMARY: &'static str = "Third party licenses";
+    const MIME: &'static str = "text/html";
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<Index, _>().route::<Updates, _>().route::<License, _>()
+}
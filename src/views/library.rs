@@ -0,0 +1,191 @@
+use crate::openapi;
+use crate::sql::*;
+use crate::utils::*;
+use askama::Template;
+use axum::response::IntoResponse;
+use itertools::Itertools;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{query_as, FromRow};
+use std::collections::{HashMap, HashSet};
+
+/// Split a soname into the name and version suffix it is stored as, e.g.
+/// "libfoo.so" and ".3". The last ".so" ending the name or followed by a
+/// version splits them, a bare name matches every version.
+fn split_soname(name: &str) -> (&str, &str) {
+    let split = name
+        .match_indices(".so")
+        .map(|(i, _)| i)
+        .filter(|&i| matches!(name.as_bytes().get(i + 3), None | Some(b'.')))
+        .last();
+
+    match split {
+        Some(i) => name.split_at(i + 3),
+        None => (name, ""),
+    }
+}
+
+typed_path!("/soname/:name", Soname, name);
+pub async fn soname(Soname { name }: Soname, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
+    struct SoPackage {
+        architecture: String,
+        depends: i32,
+        soname: String,
+        package: String,
+        version: String,
+        repo: String,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct ArchGroup<'a> {
+        architecture: &'a str,
+        provides: Vec<&'a SoPackage>,
+        depends: Vec<&'a SoPackage>,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "soname.html")]
+    struct Template<'a> {
+        name: &'a str,
+        archs: &'a Vec<ArchGroup<'a>>,
+    }
+
+    impl openapi::Route<Template<'static>> for Soname {
+        const SUMMARY: &'static str = "Packages providing or using a library";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "soname.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        packages: &'a Vec<SoPackage>,
+    }
+
+    let (soname, ver) = split_soname(&name);
+
+    let packages: &Vec<SoPackage> = &query_as(SQL_GET_SONAME_PACKAGES)
+        .bind(soname)
+        .bind(ver)
+        .fetch_all(&db.pv)
+        .await?;
+
+    if packages.is_empty() {
+        not_found!("Library \"{name}\" not found.");
+    }
+
+    let archs = &packages
+        .iter()
+        .group_by(|pkg| pkg.architecture.as_str())
+        .into_iter()
+        .map(|(architecture, pkgs)| {
+            let (depends, provides) = pkgs.partition(|pkg| pkg.depends != 0);
+            ArchGroup {
+                architecture,
+                provides,
+                depends,
+            }
+        })
+        .collect_vec();
+
+    let ctx = Template { name: &name, archs };
+    let ctx_tsv = TemplateTsv { packages };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+typed_path!("/transition", Transition);
+pub async fn transition(_: Transition, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow)]
+    struct Sobreak {
+        package: String,
+        dep_package: String,
+        deplist: Vec<String>,
+    }
+
+    #[derive(Debug, Template, Serialize, JsonSchema)]
+    #[template(path = "transition.html")]
+    struct Template<'a> {
+        pkgs: &'a Vec<String>,
+        layers: &'a Vec<Vec<String>>,
+        circular: &'a Vec<String>,
+        triggers: &'a HashMap<String, Vec<String>>,
+    }
+
+    impl openapi::Route<Template<'static>> for Transition {
+        const SUMMARY: &'static str = "Rebuild order of library transitions";
+        const PARAMS: &'static [&'static str] = &["pkgs", "type"];
+    }
+
+    #[derive(Debug, Template)]
+    #[template(path = "transition.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        layers: &'a Vec<Vec<String>>,
+        circular: &'a Vec<String>,
+        triggers: &'a HashMap<String, Vec<String>>,
+    }
+
+    let pkgs = &q.get_pkgs();
+
+    let known: Vec<(String,)> = query_as(SQL_GET_KNOWN_PACKAGES).bind(pkgs).fetch_all(&db.meta).await?;
+    let known: HashSet<_> = known.into_iter().map(|p| p.0).collect();
+    if let Some(unknown) = pkgs.iter().find(|p| !known.contains(*p)) {
+        not_found!("Package \"{unknown}\" not found.");
+    }
+
+    let sobreaks: Vec<Sobreak> = query_as(SQL_GET_SO_BREAKS_DEP_MULTI)
+        .bind(pkgs)
+        .fetch_all(&db.pv)
+        .await?;
+
+    // merge the rebuild sets of every bumped package
+    let mut data: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut triggers: HashMap<String, Vec<String>> = HashMap::new();
+    for Sobreak {
+        package,
+        dep_package,
+        deplist,
+    } in sobreaks
+    {
+        data.entry(dep_package.clone()).or_default().extend(deplist);
+        triggers.entry(dep_package).or_default().push(package);
+    }
+    let triggers = &triggers
+        .into_iter()
+        .map(|(k, v)| (k, v.into_iter().sorted().dedup().collect()))
+        .collect();
+
+    let (ref layers, ref circular) = toposort(data);
+
+    let ctx = Template {
+        pkgs,
+        layers,
+        circular,
+        triggers,
+    };
+    let ctx_tsv = TemplateTsv {
+        layers,
+        circular,
+        triggers,
+    };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<Soname, _>().route::<Transition, _>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soname() {
+        assert_eq!(split_soname("libfoo.so"), ("libfoo.so", ""));
+        assert_eq!(split_soname("libfoo.so.1.2"), ("libfoo.so", ".1.2"));
+        assert_eq!(split_soname("libfoo.sox.so.1"), ("libfoo.sox.so", ".1"));
+        assert_eq!(split_soname("libfoo.soap"), ("libfoo.soap", ""));
+        assert_eq!(split_soname("ld-linux-x86-64"), ("ld-linux-x86-64", ""));
+    }
+}
@@ -1,34 +1,55 @@
+use crate::compression::{is_compressible, precompressed, Encoding};
+use crate::config;
+use crate::openapi;
 use crate::sql::*;
 use crate::utils::*;
 use askama::Template;
 use axum::body::{boxed, Full};
-use axum::http::header;
+use axum::http::{header, HeaderMap};
 use axum::response::IntoResponse;
 use axum::response::Response;
+use axum::Extension;
 use itertools::Itertools;
 use mime_guess::mime;
+use schemars::JsonSchema;
 use serde::Serialize;
 use sqlx::{query_as, FromRow};
 use std::collections::{HashMap, HashSet};
 
 typed_path!("/static/*path", StaticFiles, path);
-pub async fn static_files(StaticFiles { path }: StaticFiles) -> Result<impl IntoResponse> {
+pub async fn static_files(StaticFiles { path }: StaticFiles, headers: HeaderMap) -> Result<impl IntoResponse> {
     #[derive(rust_embed::RustEmbed)]
     #[folder = "static"]
     struct Asset;
 
-    match Asset::get(path.as_str().trim_start_matches('/')) {
+    let path = path.as_str().trim_start_matches('/');
+    match Asset::get(path) {
         Some(content) => {
-            let body = boxed(Full::from(content.data));
             let mime = mime_guess::from_path(path).first_or_octet_stream();
-            Ok(Response::builder()
-                .header(header::CONTENT_TYPE, mime.as_ref())
-                .body(body)?)
+            let mut resp = Response::builder().header(header::CONTENT_TYPE, mime.as_ref());
+
+            // serve precompressed variants, the compression layer skips encoded responses
+            if is_compressible(&mime) {
+                resp = resp.header(header::VARY, header::ACCEPT_ENCODING.as_str());
+                if let Some(encoding) = Encoding::negotiate(&headers) {
+                    let body = precompressed(path, content.data, encoding).await?;
+                    return Ok(resp
+                        .header(header::CONTENT_ENCODING, encoding.as_str())
+                        .body(boxed(Full::from(body)))?);
+                }
+            }
+
+            Ok(resp.body(boxed(Full::from(content.data)))?)
         }
-        None => not_found!("/static{path}"),
+        None => not_found!("/static/{path}"),
     }
 }
 
+impl openapi::Route<()> for StaticFiles {
+    const SUMMARY: &'static str = "Stylesheets, scripts and images";
+    const MIME: &'static str = "application/octet-stream";
+}
+
 typed_path!("/pkgtrie.js", PkgTrie);
 pub async fn pkgtrie(_: PkgTrie, db: Ext) -> Result<impl IntoResponse> {
     #[derive(Default, Clone, Debug)]
@@ -62,7 +83,7 @@ pub async fn pkgtrie(_: PkgTrie, db: Ext) -> Result<impl IntoResponse> {
         }
     }
 
-    let pkgs: Vec<Package> = query_as("SELECT name FROM packages").fetch_all(&db.meta).await?;
+    let pkgs: Vec<Package> = query_as(SQL_GET_PACKAGE_NAMES).fetch_all(&db.meta).await?;
 
     let mut trie: Trie = Default::default();
     pkgs.iter().for_each(|pkg| trie.insert(&pkg.name));
@@ -74,12 +95,18 @@ pub async fn pkgtrie(_: PkgTrie, db: Ext) -> Result<impl IntoResponse> {
     )
 }
 
+impl openapi::Route<()> for PkgTrie {
+    const SUMMARY: &'static str = "Package names for search completion";
+    const MIME: &'static str = "application/javascript";
+}
+
 typed_path!("/list.json", PkgList);
 pub async fn pkglist(_: PkgList, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Serialize)]
+    #[derive(FromRow, Serialize, JsonSchema)]
     struct Package {
         branch: String,
         category: String,
+        #[schemars(with = "crate::openapi::Timestamp")]
         commit_time: time::OffsetDateTime,
         committer: String,
         description: String,
@@ -102,12 +129,17 @@ pub async fn pkglist(_: PkgList, db: Ext) -> Result<impl IntoResponse> {
         srctype: Option<SrcType>,
     }
 
-    #[derive(Serialize)]
-    struct PkgList {
+    #[derive(Serialize, JsonSchema)]
+    struct List {
+        #[schemars(with = "crate::openapi::Timestamp")]
         last_modified: time::OffsetDateTime,
         packages: Vec<Package>,
     }
 
+    impl openapi::Route<List> for PkgList {
+        const SUMMARY: &'static str = "Full list of packages";
+    }
+
     let mut packages: Vec<Package> = query_as(SQL_GET_PACKAGE_LIST).fetch_all(&db.meta).await?;
 
     for pkg in packages.iter_mut() {
@@ -117,7 +149,7 @@ pub async fn pkglist(_: PkgList, db: Ext) -> Result<impl IntoResponse> {
         }
     }
 
-    let res = PkgList {
+    let res = List {
         last_modified: db_last_modified(db).await?,
         packages,
     };
@@ -135,18 +167,23 @@ pub async fn cleanmirror(CleanMirror { repo }: CleanMirror, q: Query, db: Ext) -
         .map(|r| r.split(',').map(|x| x.to_string()).collect());
     let repo = strip_prefix(&repo);
 
-    #[derive(Debug, FromRow, Serialize)]
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct Deb {
         filename: String,
         removereason: String,
     }
 
-    #[derive(Debug, Template, Serialize)]
+    #[derive(Debug, Template, Serialize, JsonSchema)]
     #[template(path = "cleanmirror.txt")]
     struct Template<'a> {
         debs: Vec<&'a Deb>,
     }
 
+    impl openapi::Route<Template<'static>> for CleanMirror {
+        const SUMMARY: &'static str = "Debs that can be removed from a mirror";
+        const PARAMS: &'static [&'static str] = &["reason", "type"];
+    }
+
     let mut debs: Vec<Deb> = if get_repo(repo, &db).await?.realname == "noarch" {
         query_as(SQL_GET_DEB_LIST_NOARCH)
     } else {
@@ -186,3 +223,40 @@ pub async fn cleanmirror(CleanMirror { repo }: CleanMirror, q: Query, db: Ext) -
 
     render::<_, Template>(ctx, None, &q)
 }
+
+typed_path!("/openapi.json", OpenApi);
+pub async fn openapi_spec(_: OpenApi, Extension(global): Extension<config::Global>) -> Result<impl IntoResponse> {
+    use super::{api, graph, index, library, package, repo, search};
+
+    let spec = [
+        index::document,
+        search::document,
+        package::document,
+        graph::document,
+        library::document,
+        repo::document,
+        api::document,
+        crate::health::document,
+        document,
+    ]
+    .into_iter()
+    .fold(openapi::Spec::new(), |spec, document| document(spec))
+    .finish(&global.base_url);
+
+    Ok(build_resp(
+        mime::APPLICATION_JSON.as_ref(),
+        serde_json::to_string(&spec)?,
+    ))
+}
+
+impl openapi::Route<serde_json::Value> for OpenApi {
+    const SUMMARY: &'static str = "This document";
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<CleanMirror, _>()
+        .route::<PkgList, _>()
+        .route::<PkgTrie, _>()
+        .route::<StaticFiles, _>()
+        .route::<OpenApi, _>()
+}
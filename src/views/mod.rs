@@ -1,11 +1,17 @@
+mod api;
+mod graph;
 mod index;
+mod library;
 mod misc;
 mod package;
 mod repo;
 mod search;
 
+pub use api::{api_changelog, api_files, api_package, api_repo, api_repos, api_revdep, api_search};
+pub use graph::{closure, graph};
 pub use index::{index, license, updates};
-pub use misc::{cleanmirror, pkglist, pkgtrie, static_files};
-pub use package::{changelog, files, packages, revdep};
-pub use repo::{ghost, lagging, missing, repo};
-pub use search::search;
+pub use library::{soname, transition};
+pub use misc::{cleanmirror, openapi_spec, pkglist, pkgtrie, static_files};
+pub use package::{changelog, diff, files, packages, revdep};
+pub use repo::{broken, conflicts, ghost, lagging, matrix, missing, repo, repodiff};
+pub use search::{filesearch, search};
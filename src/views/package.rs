@@ -1,12 +1,16 @@
+use crate::config;
+use crate::feed;
 use crate::filters;
+use crate::openapi;
 use crate::sql::*;
 use crate::utils::*;
-use abbs_meta_tree::package::FailArch;
-use anyhow::anyhow;
 use askama::Template;
+use axum::http::Uri;
 use axum::response::IntoResponse;
+use axum::Extension;
 use indexmap::IndexMap;
 use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::Serialize;
 use sqlx::{query, query_as, FromRow};
 use std::collections::{HashMap, HashSet};
@@ -14,7 +18,7 @@ use std::iter::repeat;
 
 typed_path!("/packages/:name", RoutePackage, name);
 pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Debug, Serialize)]
+    #[derive(FromRow, Debug, Serialize, JsonSchema)]
     struct Package {
         name: String,
         tree: String,
@@ -27,6 +31,7 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         description: String,
         version: String,
         full_version: String,
+        #[schemars(with = "crate::openapi::Timestamp")]
         commit_time: time::OffsetDateTime,
         committer: String,
         dependency: String,
@@ -38,13 +43,13 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         spec_path: String,
     }
 
-    #[derive(Debug, Serialize)]
+    #[derive(Debug, Serialize, JsonSchema)]
     struct MatrixRow {
         repo: String,
         meta: Vec<DpkgMeta>,
     }
 
-    #[derive(Default, Clone, Debug, Serialize)]
+    #[derive(Default, Clone, Debug, Serialize, JsonSchema)]
     struct DpkgMeta {
         hasmeta: bool,
         version: String,
@@ -64,7 +69,7 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         size: i64,
     }
 
-    #[derive(Debug, Serialize)]
+    #[derive(Debug, Serialize, JsonSchema)]
     struct Version {
         testing: bool,
         version: String,
@@ -78,7 +83,7 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         fullver: String,
     }
 
-    #[derive(FromRow, Serialize, Debug)]
+    #[derive(FromRow, Serialize, Debug, JsonSchema)]
     struct PackageError {
         message: String,
         path: String,
@@ -96,7 +101,7 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         pub spec_path: String,
     }
 
-    #[derive(Template, Debug, Serialize)]
+    #[derive(Template, Debug, Serialize, JsonSchema)]
     #[template(path = "package.html")]
     struct Template<'a> {
         pkg: &'a Package,
@@ -118,6 +123,11 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
         version_matrix: Vec<MatrixRow>,
     }
 
+    impl openapi::Route<Template<'static>> for RoutePackage {
+        const SUMMARY: &'static str = "Package details";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
     let mut pkg: Option<Package> = query_as(SQL_GET_PACKAGE_INFO)
         .bind(&name)
         .fetch_optional(&db.meta)
@@ -247,22 +257,7 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
     let reponames = if pkg.noarch {
         vec!["noarch".into()]
     } else if !pkg.tree_category.is_empty() & !pkg.fail_arch.is_empty() {
-        let fail_arch = FailArch::from(&pkg.fail_arch).map_err(|_| anyhow!("invalid FAIL_ARCH format"))?;
-        db_repos(&db)
-            .await?
-            .into_values()
-            .filter_map(|r| {
-                let flag = match &fail_arch {
-                    FailArch::Include(v) => !v.contains(&r.realname),
-                    FailArch::Exclude(v) => v.contains(&r.realname),
-                };
-                if (r.category == pkg.tree_category) & (r.realname != "noarch") & flag {
-                    Some(r.realname)
-                } else {
-                    None
-                }
-            })
-            .collect()
+        expected_repos(&db_repos(&db).await?, &pkg.tree_category, &pkg.fail_arch)?
     } else {
         dpkgs.iter().map(|p| p.reponame.clone()).collect()
     };
@@ -387,8 +382,14 @@ pub async fn packages(RoutePackage { name }: RoutePackage, q: Query, db: Ext) ->
 }
 
 typed_path!("/changelog/:name", Changelog, name);
-pub async fn changelog(Changelog { name }: Changelog, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(Debug, FromRow, Serialize)]
+pub async fn changelog(
+    Changelog { name }: Changelog,
+    q: Query,
+    db: Ext,
+    uri: Uri,
+    Extension(global): Extension<config::Global>,
+) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct Change {
         pub package: String,
         pub githash: String,
@@ -399,6 +400,7 @@ pub async fn changelog(Changelog { name }: Changelog, q: Query, db: Ext) -> Resu
         pub message: String,
         pub maintainer_name: String,
         pub maintainer_email: String,
+        #[schemars(with = "crate::openapi::Timestamp")]
         pub timestamp: time::OffsetDateTime,
     }
 
@@ -411,12 +413,38 @@ pub async fn changelog(Changelog { name }: Changelog, q: Query, db: Ext) -> Resu
         not_found!("Package \"{name}\" not found.");
     }
 
-    #[derive(Template, Serialize)]
+    if let Some(format) = feed::Format::from_type(q.get_type()) {
+        let base_url = global.base_url.trim_end_matches('/');
+        let feed = feed::Feed {
+            title: format!("Changes of {name}"),
+            link: format!("{base_url}/packages/{name}"),
+            self_link: format!("{base_url}{uri}"),
+            entries: changes
+                .iter()
+                .map(|ch| feed::Entry {
+                    title: format!("{} {}", ch.package, ch.version),
+                    link: format!("https://github.com/AOSC-Dev/{}/commit/{}", ch.tree, ch.githash),
+                    author: ch.maintainer_name.clone(),
+                    summary: ch.message.clone(),
+                    updated: ch.timestamp,
+                })
+                .collect(),
+        };
+
+        return Ok(feed.render(format));
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "changelog.txt")]
     struct Template {
         changes: Vec<Change>,
     }
 
+    impl openapi::Route<Template> for Changelog {
+        const SUMMARY: &'static str = "Changelog of a package";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
     let ctx = Template { changes };
 
     render::<_, Template>(ctx, None, &q)
@@ -424,15 +452,12 @@ pub async fn changelog(Changelog { name }: Changelog, q: Query, db: Ext) -> Resu
 
 typed_path!("/revdep/:name", Revdep, name);
 pub async fn revdep(Revdep { name }: Revdep, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    let res = query("SELECT 1 FROM packages WHERE name = $1")
-        .bind(&name)
-        .fetch_optional(&db.meta)
-        .await?;
+    let res = query(SQL_PACKAGE_EXISTS).bind(&name).fetch_optional(&db.meta).await?;
     if res.is_none() {
         not_found!("Package \"{name}\" not found.");
     }
 
-    #[derive(Debug, FromRow, Serialize)]
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct RevDep {
         package: String,
         version: String,
@@ -440,13 +465,13 @@ pub async fn revdep(Revdep { name }: Revdep, q: Query, db: Ext) -> Result<impl I
         architecture: String,
     }
 
-    #[derive(Debug, Serialize)]
+    #[derive(Debug, Serialize, JsonSchema)]
     struct TemplateRevDep<'a> {
         description: &'a str,
         deps: Vec<&'a &'a RevDep>,
     }
 
-    #[derive(Debug, Template, Serialize)]
+    #[derive(Debug, Template, Serialize, JsonSchema)]
     #[template(path = "revdep.html")]
     struct Template<'a> {
         name: &'a String,
@@ -457,7 +482,12 @@ pub async fn revdep(Revdep { name }: Revdep, q: Query, db: Ext) -> Result<impl I
         sorevdeps: &'a HashMap<String, Vec<String>>,
     }
 
-    #[derive(Debug, Template, Serialize)]
+    impl openapi::Route<Template<'static>> for Revdep {
+        const SUMMARY: &'static str = "Reverse dependencies of a package";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
+    #[derive(Debug, Template, Serialize, JsonSchema)]
     #[template(path = "revdep.tsv", escape = "none")]
     struct TemplateTsv<'a> {
         revdeps: &'a Vec<TemplateRevDep<'a>>,
@@ -504,49 +534,14 @@ pub async fn revdep(Revdep { name }: Revdep, q: Query, db: Ext) -> Result<impl I
         })
         .collect_vec();
 
-    let sobreaks: Vec<Sobreak> = query_as("SELECT dep_package, deplist FROM v_so_breaks_dep WHERE package = $1")
-        .bind(&name)
-        .fetch_all(&db.pv)
-        .await?;
+    let sobreaks: Vec<Sobreak> = query_as(SQL_GET_SOBREAKS).bind(&name).fetch_all(&db.pv).await?;
 
-    let toposort = |sobreaks: Vec<Sobreak>| {
-        let mut data: HashMap<_, _> = sobreaks
+    let (ref sobreaks, ref sobreaks_circular) = toposort(
+        sobreaks
             .into_iter()
             .map(|p| (p.dep_package, HashSet::from_iter(p.deplist)))
-            .collect();
-        let mut sobreaks = vec![];
-
-        loop {
-            let ordered: HashSet<_> = data
-                .iter()
-                .filter(|(_, dep)| dep.is_empty())
-                .map(|(pkg, _)| pkg.to_string())
-                .collect();
-
-            if ordered.is_empty() {
-                break;
-            }
-
-            data = data
-                .into_iter()
-                .filter_map(|(item, dep)| {
-                    if !ordered.contains(&item) {
-                        Some((item, &dep - &ordered))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            sobreaks.push(ordered.into_iter().sorted().collect_vec());
-        }
-
-        let circular = data.into_keys().collect_vec();
-        sobreaks.reverse();
-        (sobreaks, circular)
-    };
-
-    let (ref sobreaks, ref sobreaks_circular) = toposort(sobreaks);
+            .collect(),
+    );
 
     let sorevdeps: Vec<(String, String)> = query_as(SQL_GET_PACKAGE_SO_REVDEPS)
         .bind(&name)
@@ -596,7 +591,7 @@ pub async fn files(
 ) -> Result<impl IntoResponse> {
     let repo = format!("{reponame}/{branch}");
 
-    #[derive(Debug, FromRow, Serialize)]
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct Package {
         package: String,
         version: String,
@@ -620,7 +615,7 @@ pub async fn files(
         soname: Option<String>,
     }
 
-    #[derive(Debug, FromRow, Serialize)]
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct File {
         filename: Option<String>,
         size: i64,
@@ -632,7 +627,7 @@ pub async fn files(
         gname: String,
     }
 
-    #[derive(Template, Debug, Serialize)]
+    #[derive(Template, Debug, Serialize, JsonSchema)]
     #[template(path = "files.html")]
     struct Template<'a> {
         files: &'a Vec<File>,
@@ -642,6 +637,11 @@ pub async fn files(
         pkg: Package,
     }
 
+    impl openapi::Route<Template<'static>> for Files {
+        const SUMMARY: &'static str = "Files in a deb";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
     #[derive(Template, Debug)]
     #[template(path = "files.tsv", escape = "none")]
     struct TemplateTsv<'a> {
@@ -660,7 +660,7 @@ pub async fn files(
         not_found!("Package \"{name}\" ({version}) not found in {repo}");
     };
 
-    let pkg_debtime = query_as("SELECT debtime FROM pv_packages WHERE filename=$1")
+    let pkg_debtime = query_as(SQL_GET_DEB_TIME)
         .bind(&pkg.filename)
         .fetch_optional(&db.pv)
         .await?
@@ -704,3 +704,245 @@ pub async fn files(
 
     render(ctx, Some(ctx_tsv), &q)
 }
+
+/// Items only present on one side of a diff.
+#[derive(Debug, Serialize, JsonSchema)]
+struct SetDiff {
+    name: String,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl SetDiff {
+    fn new(name: &str, from: &[String], to: &[String]) -> Self {
+        Self {
+            name: name.to_string(),
+            added: to.iter().filter(|x| !from.contains(x)).cloned().collect(),
+            removed: from.iter().filter(|x| !to.contains(x)).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+typed_path!("/diff/:name", Diff, name);
+pub async fn diff(Diff { name }: Diff, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
+    struct Package {
+        version: String,
+        repo: String,
+        installed_size: i64,
+        filename: String,
+    }
+
+    #[derive(Debug, FromRow, Serialize, Clone, PartialEq, Eq, JsonSchema)]
+    struct File {
+        filename: Option<String>,
+        size: i64,
+        ftype: i16,
+        perm: i32,
+        uid: i64,
+        gid: i64,
+        uname: String,
+        gname: String,
+    }
+
+    #[derive(Debug, FromRow)]
+    struct SoDep {
+        depends: i32,
+        soname: Option<String>,
+    }
+
+    #[derive(Debug, FromRow)]
+    struct DebDependency {
+        relationship: String,
+        value: String,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct FileChange {
+        filename: String,
+        from: File,
+        to: File,
+    }
+
+    struct Build {
+        pkg: Package,
+        files: Vec<File>,
+        sodepends: Vec<String>,
+        soprovides: Vec<String>,
+        dependencies: Vec<DebDependency>,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "diff.html")]
+    struct Template<'a> {
+        name: &'a str,
+        from: Package,
+        to: Package,
+        added: &'a Vec<File>,
+        removed: &'a Vec<File>,
+        changed: &'a Vec<FileChange>,
+        sonames: &'a Vec<SetDiff>,
+        dependencies: &'a Vec<SetDiff>,
+    }
+
+    impl openapi::Route<Template<'static>> for Diff {
+        const SUMMARY: &'static str = "Compare two builds of a package";
+        const PARAMS: &'static [&'static str] = &["from", "to", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "diff.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        added: &'a Vec<File>,
+        removed: &'a Vec<File>,
+        changed: &'a Vec<FileChange>,
+        sonames: &'a Vec<SetDiff>,
+        dependencies: &'a Vec<SetDiff>,
+    }
+
+    async fn fetch_build(db: &Ext, name: &str, build: Option<&str>) -> Result<Build> {
+        // repo names contain a slash themselves, e.g. amd64/stable/1.0-1
+        let Some((repo, version)) = build.and_then(|b| b.rsplit_once('/')) else {
+            return Err(Error::NotSupported(
+                "both from and to have to be given as repo/version".into(),
+            ));
+        };
+
+        let pkg: Option<Package> = query_as(SQL_GET_PACKAGE_DEB_LOCAL)
+            .bind(name)
+            .bind(version)
+            .bind(repo)
+            .fetch_optional(&db.meta)
+            .await?;
+        let Some(pkg) = pkg else {
+            not_found!("Package \"{name}\" ({version}) not found in {repo}");
+        };
+
+        let files = query_as(SQL_GET_PACKAGE_DEB_FILES)
+            .bind(name)
+            .bind(version)
+            .bind(repo)
+            .fetch_all(&db.pv)
+            .await?;
+
+        let sodep: Vec<SoDep> = query_as(SQL_GET_PACKAGE_SODEP)
+            .bind(name)
+            .bind(version)
+            .bind(repo)
+            .fetch_all(&db.pv)
+            .await?;
+        let (sodepends, soprovides): (Vec<_>, Vec<_>) = sodep
+            .into_iter()
+            .filter(|s| s.soname.is_some())
+            .partition(|s| s.depends != 0);
+
+        let dependencies = query_as(SQL_GET_DEB_DEPENDENCIES)
+            .bind(name)
+            .bind(version)
+            .bind(repo)
+            .fetch_all(&db.meta)
+            .await?;
+
+        Ok(Build {
+            pkg,
+            files,
+            sodepends: sodepends.into_iter().filter_map(|s| s.soname).collect(),
+            soprovides: soprovides.into_iter().filter_map(|s| s.soname).collect(),
+            dependencies,
+        })
+    }
+
+    let from = fetch_build(&db, &name, q.get_from()).await?;
+    let to = fetch_build(&db, &name, q.get_to()).await?;
+
+    let from_files: HashMap<_, _> = from.files.iter().map(|f| (&f.filename, f)).collect();
+    let to_files: HashMap<_, _> = to.files.iter().map(|f| (&f.filename, f)).collect();
+
+    let added = &to
+        .files
+        .iter()
+        .filter(|f| !from_files.contains_key(&f.filename))
+        .cloned()
+        .collect_vec();
+    let removed = &from
+        .files
+        .iter()
+        .filter(|f| !to_files.contains_key(&f.filename))
+        .cloned()
+        .collect_vec();
+    let changed = &from
+        .files
+        .iter()
+        .filter_map(|f| {
+            let new = to_files.get(&f.filename)?;
+            (f != *new).then(|| FileChange {
+                filename: f.filename.clone().unwrap_or_default(),
+                from: f.clone(),
+                to: (*new).clone(),
+            })
+        })
+        .collect_vec();
+
+    let sonames = &[
+        SetDiff::new("Library depends", &from.sodepends, &to.sodepends),
+        SetDiff::new("Library provides", &from.soprovides, &to.soprovides),
+    ]
+    .into_iter()
+    .filter(|d| !d.is_empty())
+    .collect_vec();
+
+    // compare each relationship field entry by entry
+    let fields = |build: &Build, rel: &str| {
+        build
+            .dependencies
+            .iter()
+            .filter(|d| d.relationship == rel)
+            .flat_map(|d| d.value.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect_vec()
+    };
+    let dependencies = &from
+        .dependencies
+        .iter()
+        .chain(to.dependencies.iter())
+        .map(|d| d.relationship.as_str())
+        .unique()
+        .sorted()
+        .map(|rel| SetDiff::new(rel, &fields(&from, rel), &fields(&to, rel)))
+        .filter(|d| !d.is_empty())
+        .collect_vec();
+
+    let ctx = Template {
+        name: &name,
+        from: from.pkg,
+        to: to.pkg,
+        added,
+        removed,
+        changed,
+        sonames,
+        dependencies,
+    };
+
+    let ctx_tsv = TemplateTsv {
+        added,
+        removed,
+        changed,
+        sonames,
+        dependencies,
+    };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<RoutePackage, _>()
+        .route::<Changelog, _>()
+        .route::<Revdep, _>()
+        .route::<Files, _>()
+        .route::<Diff, _>()
+}
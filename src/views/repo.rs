@@ -1,12 +1,15 @@
-use crate::db::{Page, Paginator};
+use crate::db::{fetch_page, Page, Paging};
 use crate::filters;
+use crate::openapi;
 use crate::sql::*;
 use crate::utils::*;
 use askama::Template;
 use axum::response::IntoResponse;
 use itertools::Itertools;
+use schemars::JsonSchema;
 use serde::Serialize;
 use sqlx::{query_as, FromRow};
+use std::collections::HashMap;
 
 typed_path!("/repo/*repo", RouteRepo, repo);
 pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<impl IntoResponse> {
@@ -19,7 +22,7 @@ pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<im
         status: i32,
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, JsonSchema)]
     struct PackageTemplate {
         ver_compare: i32,
         name: String,
@@ -28,7 +31,7 @@ pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<im
         status: i32,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "repo.html")]
     struct Template<'a> {
         packages: &'a Vec<PackageTemplate>,
@@ -36,7 +39,12 @@ pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<im
         page: Page,
     }
 
-    #[derive(Template, Serialize)]
+    impl openapi::Route<Template<'static>> for RouteRepo {
+        const SUMMARY: &'static str = "Packages in a repository";
+        const PARAMS: &'static [&'static str] = &["page", "cursor", "type"];
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "repo.tsv", escape = "none")]
     struct TemplateTsv<'a> {
         packages: &'a Vec<PackageTemplate>,
@@ -45,10 +53,8 @@ pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<im
     let repo = strip_prefix(&repo);
     get_repo(repo, &db).await?;
 
-    let (packages, page): (Vec<Package>, _) = query_as(SQL_GET_PACKAGE_REPO)
-        .bind(repo)
-        .fetch_page(&db.meta, q.get_page())
-        .await?;
+    let (packages, page): (Vec<Package>, _) =
+        fetch_page(&db.meta, q.get_page()?, || query_as(SQL_GET_PACKAGE_REPO).bind(repo)).await?;
 
     let packages = &packages
         .into_iter()
@@ -88,14 +94,14 @@ pub async fn repo(RouteRepo { repo }: RouteRepo, q: Query, db: Ext) -> Result<im
 
 typed_path!("/lagging/*repo", Lagging, repo);
 pub async fn lagging(Lagging { repo }: Lagging, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Debug, Serialize)]
+    #[derive(FromRow, Debug, Serialize, JsonSchema)]
     struct Package {
         name: String,
         dpkg_version: String,
         full_version: String,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "lagging.html")]
     struct Template<'a> {
         page: Page,
@@ -103,6 +109,11 @@ pub async fn lagging(Lagging { repo }: Lagging, q: Query, db: Ext) -> Result<imp
         packages: &'a Vec<Package>,
     }
 
+    impl openapi::Route<Template<'static>> for Lagging {
+        const SUMMARY: &'static str = "Packages whose deb is older than the source";
+        const PARAMS: &'static [&'static str] = &["page", "cursor", "type"];
+    }
+
     #[derive(Template)]
     #[template(path = "lagging.tsv", escape = "none")]
     struct TemplateTsv<'a> {
@@ -112,11 +123,10 @@ pub async fn lagging(Lagging { repo }: Lagging, q: Query, db: Ext) -> Result<imp
     let repo = strip_prefix(&repo);
     let architecture = get_repo(repo, &db).await?.architecture;
 
-    let (ref packages, page): (Vec<Package>, _) = query_as(SQL_GET_PACKAGE_LAGGING)
-        .bind(repo)
-        .bind(architecture)
-        .fetch_page(&db.meta, q.get_page())
-        .await?;
+    let (ref packages, page): (Vec<Package>, _) = fetch_page(&db.meta, q.get_page()?, || {
+        query_as(SQL_GET_PACKAGE_LAGGING).bind(repo).bind(&architecture)
+    })
+    .await?;
 
     if packages.is_empty() {
         not_found!("There's no lagging packages.");
@@ -135,7 +145,7 @@ pub async fn lagging(Lagging { repo }: Lagging, q: Query, db: Ext) -> Result<imp
 
 typed_path!("/missing/*repo", Missing, repo);
 pub async fn missing(Missing { repo }: Missing, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(FromRow, Debug, Serialize)]
+    #[derive(FromRow, Debug, Serialize, JsonSchema)]
     struct Package {
         name: String,
         description: String,
@@ -144,7 +154,7 @@ pub async fn missing(Missing { repo }: Missing, q: Query, db: Ext) -> Result<imp
         tree_category: String,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "missing.html")]
     struct Template<'a> {
         page: Page,
@@ -152,6 +162,11 @@ pub async fn missing(Missing { repo }: Missing, q: Query, db: Ext) -> Result<imp
         packages: &'a Vec<Package>,
     }
 
+    impl openapi::Route<Template<'static>> for Missing {
+        const SUMMARY: &'static str = "Packages without a deb in a repository";
+        const PARAMS: &'static [&'static str] = &["page", "cursor", "type"];
+    }
+
     #[derive(Template)]
     #[template(path = "missing.tsv", escape = "none")]
     struct TemplateTsv<'a> {
@@ -161,12 +176,13 @@ pub async fn missing(Missing { repo }: Missing, q: Query, db: Ext) -> Result<imp
     let repo = strip_prefix(&repo);
     let repo = get_repo(repo, &db).await?;
 
-    let (ref packages, page): (Vec<Package>, _) = query_as(SQL_GET_PACKAGE_MISSING)
-        .bind(&repo.realname)
-        .bind(&repo.architecture)
-        .bind(&repo.realname)
-        .fetch_page(&db.meta, q.get_page())
-        .await?;
+    let (ref packages, page): (Vec<Package>, _) = fetch_page(&db.meta, q.get_page()?, || {
+        query_as(SQL_GET_PACKAGE_MISSING)
+            .bind(&repo.realname)
+            .bind(&repo.architecture)
+            .bind(&repo.realname)
+    })
+    .await?;
 
     if packages.is_empty() {
         not_found!("There's no missing packages.");
@@ -185,13 +201,13 @@ pub async fn missing(Missing { repo }: Missing, q: Query, db: Ext) -> Result<imp
 
 typed_path!("/ghost/*repo", Ghost, repo);
 pub async fn ghost(Ghost { repo }: Ghost, q: Query, db: Ext) -> Result<impl IntoResponse> {
-    #[derive(Debug, FromRow, Serialize)]
+    #[derive(Debug, FromRow, Serialize, JsonSchema)]
     struct Package {
         name: String,
         dpkg_version: String,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "ghost.html")]
     struct Template<'a> {
         packages: &'a Vec<Package>,
@@ -199,7 +215,12 @@ pub async fn ghost(Ghost { repo }: Ghost, q: Query, db: Ext) -> Result<impl Into
         page: Page,
     }
 
-    #[derive(Template, Serialize)]
+    impl openapi::Route<Template<'static>> for Ghost {
+        const SUMMARY: &'static str = "Debs without a source package";
+        const PARAMS: &'static [&'static str] = &["page", "cursor", "type"];
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "ghost.tsv", escape = "none")]
     struct TemplateTsv<'a> {
         packages: &'a Vec<Package>,
@@ -208,10 +229,8 @@ pub async fn ghost(Ghost { repo }: Ghost, q: Query, db: Ext) -> Result<impl Into
     let repo = strip_prefix(&repo);
     get_repo(repo, &db).await?;
 
-    let (ref packages, page): (Vec<Package>, _) = query_as(SQL_GET_PACKAGE_GHOST)
-        .bind(repo)
-        .fetch_page(&db.meta, q.get_page())
-        .await?;
+    let (ref packages, page): (Vec<Package>, _) =
+        fetch_page(&db.meta, q.get_page()?, || query_as(SQL_GET_PACKAGE_GHOST).bind(repo)).await?;
 
     if packages.is_empty() {
         not_found!("There's no ghost packages.");
@@ -226,3 +245,447 @@ pub async fn ghost(Ghost { repo }: Ghost, q: Query, db: Ext) -> Result<impl Into
 
     render(ctx, Some(ctx_tsv), &q)
 }
+
+typed_path!("/broken/*repo", Broken, repo);
+pub async fn broken(Broken { repo }: Broken, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "broken.html")]
+    struct Template<'a> {
+        repo: String,
+        packages: &'a Vec<BrokenDep>,
+    }
+
+    impl openapi::Route<Template<'static>> for Broken {
+        const SUMMARY: &'static str = "Debs with unsatisfiable dependencies";
+        const PARAMS: &'static [&'static str] = &["type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "broken.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        packages: &'a Vec<BrokenDep>,
+    }
+
+    let repo = strip_prefix(&repo);
+    let repo = get_repo(repo, &db).await?;
+
+    let packages = &*db_broken(&db, &repo).await?;
+    if packages.is_empty() {
+        not_found!("There's no broken packages.");
+    }
+
+    let ctx = Template {
+        repo: repo.name,
+        packages,
+    };
+
+    let ctx_tsv = TemplateTsv { packages };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+typed_path!("/conflicts/*repo", Conflicts, repo);
+pub async fn conflicts(Conflicts { repo }: Conflicts, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow)]
+    struct Overlap {
+        filename: String,
+        packages: Vec<String>,
+    }
+
+    #[derive(Debug, FromRow)]
+    struct Relation {
+        package: String,
+        dependency: String,
+        relationship: String,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct Conflict {
+        filename: String,
+        packages: Vec<String>,
+        /// declared relationships between the packages, e.g. `b PKGREP a`
+        declared: Vec<String>,
+        covered: bool,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "conflicts.html")]
+    struct Template<'a> {
+        conflicts: &'a Vec<Conflict>,
+        repo: String,
+        page: Page,
+    }
+
+    impl openapi::Route<Template<'static>> for Conflicts {
+        const SUMMARY: &'static str = "Files shipped by more than one package";
+        const PARAMS: &'static [&'static str] = &["page", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "conflicts.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        conflicts: &'a Vec<Conflict>,
+    }
+
+    let repo = strip_prefix(&repo);
+    let repo = get_repo(repo, &db).await?;
+
+    let paging = q.get_page()?;
+    if let Paging::After(_) = paging {
+        return Err(Error::NotSupported("file conflicts cannot be paged by cursor".into()));
+    }
+
+    let repos = with_noarch(&db, &repo).await?;
+    let (overlaps, page): (Vec<Overlap>, _) =
+        fetch_page(&db.pv, paging, || query_as(SQL_GET_FILE_CONFLICTS).bind(&repos)).await?;
+
+    if overlaps.is_empty() {
+        not_found!("There's no file conflicts.");
+    }
+
+    let involved = overlaps.iter().flat_map(|o| o.packages.iter()).unique().collect_vec();
+    let relations: Vec<Relation> = query_as(SQL_GET_CONFLICT_RELATIONS)
+        .bind(involved)
+        .fetch_all(&db.meta)
+        .await?;
+
+    let conflicts = &overlaps
+        .into_iter()
+        .map(|Overlap { filename, packages }| {
+            let declared = relations
+                .iter()
+                .filter(|r| packages.contains(&r.package) && packages.contains(&r.dependency))
+                .collect_vec();
+
+            // every pair of packages shipping the path has to be related
+            let covered = packages.iter().tuple_combinations().all(|(a, b)| {
+                declared
+                    .iter()
+                    .any(|r| (&r.package, &r.dependency) == (a, b) || (&r.package, &r.dependency) == (b, a))
+            });
+
+            Conflict {
+                filename,
+                declared: declared
+                    .iter()
+                    .map(|r| format!("{} {} {}", r.package, r.relationship, r.dependency))
+                    .collect(),
+                packages,
+                covered,
+            }
+        })
+        .collect_vec();
+
+    let ctx = Template {
+        conflicts,
+        repo: repo.name,
+        page,
+    };
+
+    let ctx_tsv = TemplateTsv { conflicts };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+typed_path!("/repodiff", RepoDiff);
+pub async fn repodiff(_: RepoDiff, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow)]
+    struct Package {
+        name: String,
+        dpkg_version: String,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct PackageDiff {
+        name: String,
+        a_version: String,
+        b_version: String,
+        ver_compare: i32,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "repodiff.html")]
+    struct Template<'a> {
+        a: &'a str,
+        b: &'a str,
+        #[serde(skip)]
+        repos: Vec<String>,
+        only_a: &'a Vec<PackageDiff>,
+        only_b: &'a Vec<PackageDiff>,
+        differ: &'a Vec<PackageDiff>,
+    }
+
+    impl openapi::Route<Template<'static>> for RepoDiff {
+        const SUMMARY: &'static str = "Compare the latest versions in two repositories";
+        const PARAMS: &'static [&'static str] = &["a", "b", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "repodiff.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        packages: Vec<&'a PackageDiff>,
+    }
+
+    let repos = db_repos(&db).await?.into_keys().sorted().collect_vec();
+    let (a, b) = (q.get_a().unwrap_or_default(), q.get_b().unwrap_or_default());
+
+    let (mut only_a, mut only_b, mut differ) = (vec![], vec![], vec![]);
+    if !a.is_empty() && !b.is_empty() {
+        let (repo_a, repo_b) = (get_repo(a, &db).await?, get_repo(b, &db).await?);
+
+        let packages_a: Vec<Package> = query_as(SQL_GET_REPO_VERSIONS)
+            .bind(&repo_a.name)
+            .fetch_all(&db.meta)
+            .await?;
+        let packages_b: Vec<Package> = query_as(SQL_GET_REPO_VERSIONS)
+            .bind(&repo_b.name)
+            .fetch_all(&db.meta)
+            .await?;
+
+        // both lists are sorted by name in byte order, as `str::cmp` compares
+        for pkg in packages_a
+            .into_iter()
+            .merge_join_by(packages_b, |a, b| a.name.cmp(&b.name))
+        {
+            use itertools::EitherOrBoth::*;
+            match pkg {
+                Left(a) => only_a.push(PackageDiff {
+                    name: a.name,
+                    a_version: a.dpkg_version,
+                    b_version: String::new(),
+                    ver_compare: 1,
+                }),
+                Right(b) => only_b.push(PackageDiff {
+                    name: b.name,
+                    a_version: String::new(),
+                    b_version: b.dpkg_version,
+                    ver_compare: -1,
+                }),
+                Both(a, b) => {
+                    let ver_compare = match deb_version::compare_versions(&a.dpkg_version, &b.dpkg_version) {
+                        std::cmp::Ordering::Less => -1,
+                        std::cmp::Ordering::Equal => continue,
+                        std::cmp::Ordering::Greater => 1,
+                    };
+                    differ.push(PackageDiff {
+                        name: a.name,
+                        a_version: a.dpkg_version,
+                        b_version: b.dpkg_version,
+                        ver_compare,
+                    });
+                }
+            }
+        }
+    }
+
+    let ctx = Template {
+        a,
+        b,
+        repos,
+        only_a: &only_a,
+        only_b: &only_b,
+        differ: &differ,
+    };
+
+    let ctx_tsv = TemplateTsv {
+        packages: only_a.iter().chain(&only_b).chain(&differ).collect(),
+    };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+typed_path!("/matrix", Matrix);
+pub async fn matrix(_: Matrix, q: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(Debug, FromRow)]
+    struct Package {
+        name: String,
+        tree: String,
+        tree_category: String,
+        section: String,
+        full_version: String,
+        noarch: bool,
+        fail_arch: String,
+    }
+
+    #[derive(Debug, FromRow)]
+    struct Dpkg {
+        package: String,
+        realname: String,
+        version: String,
+    }
+
+    #[derive(Debug, Default, Serialize, JsonSchema)]
+    struct Cell {
+        version: String,
+        ver_compare: i32,
+        expected: bool,
+    }
+
+    #[derive(Debug, Serialize, JsonSchema)]
+    struct Row {
+        name: String,
+        tree: String,
+        section: String,
+        full_version: String,
+        cells: Vec<Cell>,
+    }
+
+    #[derive(Debug, Default, Serialize, JsonSchema)]
+    struct Summary {
+        realname: String,
+        same: usize,
+        old: usize,
+        missing: usize,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "matrix.html")]
+    struct Template<'a> {
+        tree: &'a str,
+        section: &'a str,
+        realnames: &'a Vec<String>,
+        summary: &'a Vec<Summary>,
+        packages: &'a Vec<Row>,
+    }
+
+    impl openapi::Route<Template<'static>> for Matrix {
+        const SUMMARY: &'static str = "Architecture parity of every package";
+        const PARAMS: &'static [&'static str] = &["tree", "section", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "matrix.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        realnames: &'a Vec<String>,
+        packages: &'a Vec<Row>,
+    }
+
+    let (tree, section) = (q.get_tree(), q.get_section());
+    let packages: Vec<Package> = query_as(SQL_GET_MATRIX_PACKAGES)
+        .bind(tree)
+        .bind(section)
+        .fetch_all(&db.meta)
+        .await?;
+    if packages.is_empty() {
+        not_found!("There's no packages.");
+    }
+
+    let names = packages.iter().map(|p| &p.name).collect_vec();
+    let dpkgs: Vec<Dpkg> = query_as(SQL_GET_MATRIX_DPKG).bind(names).fetch_all(&db.meta).await?;
+    let mut versions: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+    for dpkg in &dpkgs {
+        versions
+            .entry(&dpkg.package)
+            .or_default()
+            .insert(&dpkg.realname, &dpkg.version);
+    }
+
+    // same rules as the version matrix on the package page
+    let repos = db_repos(&db).await?;
+    let mut expected_by_category = HashMap::new();
+    let mut expected = Vec::with_capacity(packages.len());
+    for pkg in &packages {
+        let realnames = if pkg.noarch {
+            vec!["noarch".to_string()]
+        } else if pkg.fail_arch.is_empty() {
+            if !expected_by_category.contains_key(&pkg.tree_category) {
+                let realnames = expected_repos(&repos, &pkg.tree_category, "")?;
+                expected_by_category.insert(&pkg.tree_category, realnames);
+            }
+            expected_by_category[&pkg.tree_category].clone()
+        } else {
+            expected_repos(&repos, &pkg.tree_category, &pkg.fail_arch)?
+        };
+        expected.push(realnames);
+    }
+
+    let realnames = &expected
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .chain(dpkgs.iter().map(|d| d.realname.as_str()))
+        .unique()
+        .sorted()
+        .map(String::from)
+        .collect_vec();
+
+    let mut summary = realnames
+        .iter()
+        .map(|realname| Summary {
+            realname: realname.clone(),
+            ..Default::default()
+        })
+        .collect_vec();
+
+    let packages = &packages
+        .into_iter()
+        .zip(expected)
+        .map(|(pkg, expected)| {
+            let versions = versions.get(pkg.name.as_str());
+            let cells = realnames
+                .iter()
+                .zip(summary.iter_mut())
+                .map(|(realname, summary)| {
+                    let expected = expected.contains(realname);
+                    let Some(version) = versions.and_then(|v| v.get(realname.as_str())) else {
+                        if expected {
+                            summary.missing += 1;
+                        }
+                        return Cell {
+                            expected,
+                            ..Default::default()
+                        };
+                    };
+
+                    let ver_compare = match deb_version::compare_versions(version, &pkg.full_version) {
+                        std::cmp::Ordering::Less => -1,
+                        std::cmp::Ordering::Equal => 0,
+                        std::cmp::Ordering::Greater => 1,
+                    };
+                    if ver_compare < 0 {
+                        summary.old += 1;
+                    } else {
+                        summary.same += 1;
+                    }
+
+                    Cell {
+                        version: version.to_string(),
+                        ver_compare,
+                        expected,
+                    }
+                })
+                .collect();
+
+            Row {
+                name: pkg.name,
+                tree: pkg.tree,
+                section: pkg.section,
+                full_version: pkg.full_version,
+                cells,
+            }
+        })
+        .collect_vec();
+
+    let ctx = Template {
+        tree: tree.unwrap_or_default(),
+        section: section.unwrap_or_default(),
+        realnames,
+        summary: &summary,
+        packages,
+    };
+
+    let ctx_tsv = TemplateTsv { realnames, packages };
+
+    render(ctx, Some(ctx_tsv), &q)
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<RouteRepo, _>()
+        .route::<Lagging, _>()
+        .route::<Missing, _>()
+        .route::<Ghost, _>()
+        .route::<Broken, _>()
+        .route::<Conflicts, _>()
+        .route::<RepoDiff, _>()
+        .route::<Matrix, _>()
+}
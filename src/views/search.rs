@@ -1,9 +1,11 @@
-use crate::db::{Page, Paginator};
+use crate::db::{fetch_page, Page, Paging};
 use crate::filters;
+use crate::openapi;
 use crate::sql::*;
 use crate::utils::*;
 use askama::Template;
 use axum::response::{IntoResponse, Redirect};
+use schemars::JsonSchema;
 use serde::Serialize;
 use sqlx::{query_as, FromRow};
 
@@ -17,7 +19,7 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
         name: String,
     }
 
-    #[derive(FromRow, Serialize)]
+    #[derive(FromRow, Serialize, JsonSchema)]
     struct PackageTemplate {
         name_highlight: String,
         full_version: String,
@@ -26,7 +28,7 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
         name: String,
     }
 
-    #[derive(Template, Serialize)]
+    #[derive(Template, Serialize, JsonSchema)]
     #[template(path = "search.html")]
     struct Template<'a> {
         q: &'a String,
@@ -34,6 +36,11 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
         page: Page,
     }
 
+    impl openapi::Route<Template<'static>> for Search {
+        const SUMMARY: &'static str = "Search packages";
+        const PARAMS: &'static [&'static str] = &["q", "noredir", "page", "type"];
+    }
+
     #[derive(Template)]
     #[template(path = "search.tsv", escape = "none")]
     struct TemplateTsv<'a> {
@@ -55,7 +62,7 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
 
     if !query.get_noredir() {
         let q = q.trim().to_lowercase().replace([' ', '_'], "-");
-        let mut row = sqlx::query("SELECT 1 FROM packages WHERE name = $1")
+        let mut row = sqlx::query(SQL_PACKAGE_EXISTS)
             .bind(&q)
             .fetch_optional(&db.meta)
             .await?;
@@ -74,17 +81,25 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
     //let qesc = format!("\"{q}\"");
     let qesc = q.to_string();
 
-    let (packages, page): (Vec<Package>, _) = query_as(SQL_SEARCH_PACKAGES_DESC)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .bind(&qesc)
-        .fetch_page(&db.meta, query.get_page())
-        .await?;
+    let paging = query.get_page()?;
+    if let Paging::After(_) = paging {
+        return Err(Error::NotSupported(
+            "search results are ranked and cannot be paged by cursor".into(),
+        ));
+    }
+
+    let (packages, page): (Vec<Package>, _) = fetch_page(&db.meta, paging, || {
+        query_as(SQL_SEARCH_PACKAGES_DESC)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+            .bind(&qesc)
+    })
+    .await?;
 
     let packages = &packages
         .into_iter()
@@ -111,3 +126,267 @@ pub async fn search(_: Search, query: Query, db: Ext) -> Result<impl IntoRespons
 
     render(ctx, Some(ctx_tsv), &query)
 }
+
+/// Translate a shell glob into an anchored regex matching full paths.
+///
+/// `*` and `?` stay within one path component while `**` spans several.
+/// Globs not starting with `/` may match in any directory.
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from(if glob.starts_with('/') { "^" } else { "^(.*/)?" });
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                let rest: String = chars.clone().collect();
+                match rest.find(']') {
+                    Some(end) if end > 0 => {
+                        let class = &rest[..end];
+                        chars.nth(class.chars().count());
+
+                        re.push('[');
+                        if let Some(class) = class.strip_prefix('!') {
+                            re.push('^');
+                            re.push_str(&class.replace('\\', "\\\\"));
+                        } else {
+                            re.push_str(&class.replace('\\', "\\\\"));
+                        }
+                        re.push(']');
+                    }
+                    // unmatched bracket is taken literally
+                    _ => re.push_str("\\["),
+                }
+            }
+            c if "\\.+()|{}^$]".contains(c) => {
+                re.push('\\');
+                re.push(c);
+            }
+            c => re.push(c),
+        }
+    }
+    re.push('$');
+
+    re
+}
+
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+/// How a `/filesearch` query is matched. The literal parts of the query let
+/// Postgres narrow the files down by name or directory before the regex is
+/// applied to the remaining ones.
+#[derive(Debug, Default, PartialEq)]
+struct FileFilter<'a> {
+    /// exact file name
+    name: Option<&'a str>,
+    /// `LIKE` pattern of the file name
+    name_like: Option<String>,
+    /// `LIKE` pattern of the directory, without the leading `/`
+    path_like: Option<String>,
+    /// regex of the full path
+    regex: Option<String>,
+}
+
+impl<'a> FileFilter<'a> {
+    /// Globs must start their file name with a literal (`libfoo*.pc`) or be
+    /// absolute with a literal directory (`/usr/lib/pkgconfig/*.pc`), so that
+    /// they never scan every file.
+    fn parse(q: &'a str) -> Result<Self> {
+        let name = q.rsplit_once('/').map_or(q, |(_, name)| name);
+
+        if !q.contains(GLOB_CHARS) {
+            // bare file names match in any directory
+            return Ok(Self {
+                name: Some(name),
+                regex: q.contains('/').then(|| glob_to_regex(q)),
+                ..Default::default()
+            });
+        }
+
+        let mut filter = Self {
+            regex: Some(glob_to_regex(q)),
+            ..Default::default()
+        };
+        if !name.contains(GLOB_CHARS) {
+            filter.name = Some(name);
+            return Ok(filter);
+        }
+
+        let literal = name.split(GLOB_CHARS).next().unwrap_or_default();
+        if !literal.is_empty() {
+            filter.name_like = Some(format!("{}%", like_escape(literal)));
+        }
+        if q.starts_with('/') {
+            let literal = q.split(GLOB_CHARS).next().unwrap_or_default();
+            if let Some((dir, _)) = literal.rsplit_once('/') {
+                let dir = dir.trim_start_matches('/');
+                if !dir.is_empty() {
+                    filter.path_like = Some(format!("{}%", like_escape(dir)));
+                }
+            }
+        }
+
+        if filter.name_like.is_none() && filter.path_like.is_none() {
+            return Err(Error::NotSupported(format!(
+                "glob \"{q}\" needs a literal start of the file name, such as libfoo*.pc, \
+                 or an absolute directory, such as /usr/lib/pkgconfig/*.pc"
+            )));
+        }
+
+        Ok(filter)
+    }
+}
+
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+typed_path!("/filesearch", FileSearch);
+pub async fn filesearch(_: FileSearch, query: Query, db: Ext) -> Result<impl IntoResponse> {
+    #[derive(FromRow, Serialize, JsonSchema)]
+    struct File {
+        package: String,
+        version: String,
+        repo: String,
+        filename: String,
+    }
+
+    #[derive(Template, Serialize, JsonSchema)]
+    #[template(path = "filesearch.html")]
+    struct Template<'a> {
+        q: &'a str,
+        repo: &'a str,
+        files: &'a Vec<File>,
+        page: Page,
+    }
+
+    impl openapi::Route<Template<'static>> for FileSearch {
+        const SUMMARY: &'static str = "Search files in debs";
+        const PARAMS: &'static [&'static str] = &["q", "repo", "page", "type"];
+    }
+
+    #[derive(Template)]
+    #[template(path = "filesearch.tsv", escape = "none")]
+    struct TemplateTsv<'a> {
+        files: &'a Vec<File>,
+    }
+
+    let repo = query.get_repo();
+    if let Some(repo) = repo {
+        get_repo(repo, &db).await?;
+    }
+
+    let q = query.get_query().as_deref().map(str::trim).unwrap_or("");
+    if q.is_empty() {
+        let ctx = Template {
+            q,
+            repo: repo.unwrap_or(""),
+            files: &vec![],
+            page: Page::default(),
+        };
+        let ctx_tsv = TemplateTsv { files: &vec![] };
+
+        return render(ctx, Some(ctx_tsv), &query);
+    }
+
+    let paging = query.get_page()?;
+    if let Paging::After(_) = paging {
+        return Err(Error::NotSupported("file search cannot be paged by cursor".into()));
+    }
+
+    let filter = FileFilter::parse(q)?;
+    let (ref files, page): (Vec<File>, _) = fetch_page(&db.pv, paging, || {
+        query_as(SQL_SEARCH_FILES)
+            .bind(filter.name)
+            .bind(&filter.name_like)
+            .bind(&filter.path_like)
+            .bind(&filter.regex)
+            .bind(repo)
+    })
+    .await?;
+
+    let ctx = Template {
+        q,
+        repo: repo.unwrap_or(""),
+        files,
+        page,
+    };
+    let ctx_tsv = TemplateTsv { files };
+
+    render(ctx, Some(ctx_tsv), &query)
+}
+
+pub fn document(spec: openapi::Spec) -> openapi::Spec {
+    spec.route::<Search, _>().route::<FileSearch, _>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert_eq!(glob_to_regex("/usr/**/*.pc"), r"^/usr/.*/[^/]*\.pc$");
+        assert_eq!(glob_to_regex("/usr/lib/lib[!a-c]?.so"), r"^/usr/lib/lib[^a-c][^/]\.so$");
+        assert_eq!(glob_to_regex("lib[xy].so.*"), r"^(.*/)?lib[xy]\.so\.[^/]*$");
+        // unmatched and empty brackets are literal
+        assert_eq!(glob_to_regex("/foo[bar"), r"^/foo\[bar$");
+        assert_eq!(glob_to_regex("/foo[]"), r"^/foo\[\]$");
+        // relative globs match in any directory
+        assert_eq!(glob_to_regex("bin/*sh"), r"^(.*/)?bin/[^/]*sh$");
+    }
+
+    #[test]
+    fn file_filter() {
+        let filter = |q| FileFilter::parse(q).unwrap();
+
+        assert_eq!(
+            filter("libfoo.so.3"),
+            FileFilter {
+                name: Some("libfoo.so.3"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            filter("/usr/bin/bash"),
+            FileFilter {
+                name: Some("bash"),
+                regex: Some(r"^/usr/bin/bash$".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            filter("/usr/*/bash"),
+            FileFilter {
+                name: Some("bash"),
+                regex: Some(r"^/usr/[^/]*/bash$".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            filter("lib_foo*.pc"),
+            FileFilter {
+                name_like: Some(r"lib\_foo%".into()),
+                regex: Some(r"^(.*/)?lib_foo[^/]*\.pc$".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            filter("/usr/lib/pkgconfig/*.pc"),
+            FileFilter {
+                path_like: Some("usr/lib/pkgconfig%".into()),
+                regex: Some(r"^/usr/lib/pkgconfig/[^/]*\.pc$".into()),
+                ..Default::default()
+            }
+        );
+
+        for q in ["*.pc", "/*/*.so", "bin/*", "/**"] {
+            assert!(FileFilter::parse(q).is_err(), "{q}");
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Background task posting package changes to webhooks, see `[webhook]` in
+//! `config.toml`.
+//!
+//! The database is scanned every `interval` seconds and compared with the
+//! previous scan. The first scan only records the current state, so nothing
+//! is sent for changes made while the site was down.
+
+use crate::config;
+use crate::db::Db;
+use crate::sql::*;
+use crate::utils::{db_repos, Ext, Result};
+use axum::Extension;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::{query_as, FromRow};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// a new version has been committed to the source tree
+    VersionCommitted {
+        package: String,
+        old_version: Option<String>,
+        version: String,
+    },
+    /// a new deb has been published to a repo
+    DebPublished {
+        package: String,
+        repo: String,
+        old_version: Option<String>,
+        version: String,
+    },
+    /// the deb in a repo is older than the source tree
+    Lagging {
+        package: String,
+        repo: String,
+        dpkg_version: String,
+        version: String,
+    },
+    /// the source tree has a package the repo lacks
+    Missing {
+        package: String,
+        repo: String,
+        version: String,
+    },
+}
+
+impl Event {
+    fn package(&self) -> &str {
+        match self {
+            Event::VersionCommitted { package, .. }
+            | Event::DebPublished { package, .. }
+            | Event::Lagging { package, .. }
+            | Event::Missing { package, .. } => package,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Snapshot {
+    /// package -> full version
+    versions: HashMap<String, String>,
+    /// (package, repo) -> deb version
+    debs: HashMap<(String, String), String>,
+    /// (package, repo) -> (deb version, full version)
+    lagging: HashMap<(String, String), (String, String)>,
+    /// (package, repo) -> full version
+    missing: HashMap<(String, String), String>,
+}
+
+/// The last scan and delivery number, kept by the caller across restarts of
+/// [`run`] so that a reload neither misses changes nor reuses delivery ids.
+#[derive(Default)]
+pub struct LastScan(Mutex<(Option<Snapshot>, u64)>);
+
+impl Snapshot {
+    async fn scan(db: &Ext) -> Result<Self> {
+        #[derive(FromRow)]
+        struct Version {
+            name: String,
+            full_version: String,
+        }
+
+        #[derive(FromRow)]
+        struct Deb {
+            package: String,
+            repo: String,
+            dpkg_version: String,
+        }
+
+        #[derive(FromRow)]
+        struct Lagging {
+            name: String,
+            dpkg_version: String,
+            full_version: String,
+        }
+
+        let mut snapshot = Snapshot::default();
+        let repos = db_repos(db).await?;
+        // on the primary, so that consecutive scans never go back in time
+        let conn = &mut db.meta.primary().await?;
+
+        let versions: Vec<Version> = query_as(SQL_GET_WEBHOOK_VERSIONS).fetch_all(&mut *conn).await?;
+        snapshot.versions = versions.into_iter().map(|v| (v.name, v.full_version)).collect();
+
+        let debs: Vec<Deb> = query_as(SQL_GET_WEBHOOK_DEBS).fetch_all(&mut *conn).await?;
+        snapshot.debs = debs
+            .into_iter()
+            .map(|d| ((d.package, d.repo), d.dpkg_version))
+            .collect();
+
+        for repo in repos.values() {
+            let lagging: Vec<Lagging> = query_as(SQL_GET_PACKAGE_LAGGING)
+                .bind(&repo.name)
+                .bind(&repo.architecture)
+                .fetch_all(&mut *conn)
+                .await?;
+            snapshot.lagging.extend(
+                lagging
+                    .into_iter()
+                    .map(|l| ((l.name, repo.name.clone()), (l.dpkg_version, l.full_version))),
+            );
+
+            // same repos as the missing column on the index
+            if (repo.testing == 0) & (repo.category != "overlay") {
+                let missing: Vec<Version> = query_as(SQL_GET_PACKAGE_MISSING)
+                    .bind(&repo.realname)
+                    .bind(&repo.architecture)
+                    .bind(&repo.realname)
+                    .fetch_all(&mut *conn)
+                    .await?;
+                snapshot.missing.extend(
+                    missing
+                        .into_iter()
+                        .map(|m| ((m.name, repo.name.clone()), m.full_version)),
+                );
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Events turning `self` into `new`.
+    fn diff(&self, new: &Snapshot) -> Vec<Event> {
+        let mut events = vec![];
+
+        for (package, version) in &new.versions {
+            let old_version = self.versions.get(package);
+            if old_version != Some(version) {
+                events.push(Event::VersionCommitted {
+                    package: package.clone(),
+                    old_version: old_version.cloned(),
+                    version: version.clone(),
+                });
+            }
+        }
+
+        for ((package, repo), version) in &new.debs {
+            let old_version = self.debs.get(&(package.clone(), repo.clone()));
+            if old_version != Some(version) {
+                events.push(Event::DebPublished {
+                    package: package.clone(),
+                    repo: repo.clone(),
+                    old_version: old_version.cloned(),
+                    version: version.clone(),
+                });
+            }
+        }
+
+        for (key, (dpkg_version, version)) in &new.lagging {
+            if !self.lagging.contains_key(key) {
+                events.push(Event::Lagging {
+                    package: key.0.clone(),
+                    repo: key.1.clone(),
+                    dpkg_version: dpkg_version.clone(),
+                    version: version.clone(),
+                });
+            }
+        }
+
+        for (key, version) in &new.missing {
+            if !self.missing.contains_key(key) {
+                events.push(Event::Missing {
+                    package: key.0.clone(),
+                    repo: key.1.clone(),
+                    version: version.clone(),
+                });
+            }
+        }
+
+        events.sort_by(|a, b| a.package().cmp(b.package()));
+        events
+    }
+}
+
+#[derive(Serialize)]
+struct Delivery<'a> {
+    id: &'a str,
+    timestamp: i64,
+    events: &'a [Event],
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    id: &'a str,
+    url: &'a str,
+    attempt: u32,
+    timestamp: i64,
+    status: Option<u16>,
+    error: Option<String>,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn log_delivery(config: &config::Webhook, entry: &LogEntry<'_>) {
+    if config.delivery_log.is_empty() {
+        return;
+    }
+
+    let res = async {
+        let line = serde_json::to_string(entry)? + "\n";
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.delivery_log)
+            .await?;
+        file.write_all(line.as_bytes()).await
+    };
+    if let Err(e) = res.await {
+        error!("failed to write webhook delivery log: {:?}", e);
+    }
+}
+
+/// POST `body` to `url`, retrying with exponential backoff.
+async fn deliver(client: reqwest::Client, config: Arc<config::Webhook>, url: String, id: String, body: Vec<u8>) {
+    let signature = config.secret.as_deref().map(|secret| sign(secret, &body));
+
+    for attempt in 1..=config.max_attempts {
+        let mut req = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Packages-Delivery", &id)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            req = req.header("X-Packages-Signature", signature);
+        }
+
+        let res = req.send().await.and_then(|res| res.error_for_status());
+        let (status, error) = match &res {
+            Ok(res) => (Some(res.status().as_u16()), None),
+            Err(e) => (e.status().map(|s| s.as_u16()), Some(e.to_string())),
+        };
+        log_delivery(
+            &config,
+            &LogEntry {
+                id: &id,
+                url: &url,
+                attempt,
+                timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+                status,
+                error,
+            },
+        )
+        .await;
+
+        match res {
+            Ok(_) => {
+                info!("webhook delivery {id} to {url} succeeded");
+                return;
+            }
+            Err(e) if attempt < config.max_attempts => {
+                let delay = config.retry_delay.saturating_mul(1 << (attempt - 1).min(16));
+                warn!("webhook delivery {id} to {url} failed, retrying in {delay}s: {e}");
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+            }
+            Err(e) => error!("webhook delivery {id} to {url} failed, giving up: {e}"),
+        }
+    }
+}
+
+/// Scan and deliver until aborted. Deliveries already under way when the
+/// task is aborted on reload finish with the settings they started with.
+pub async fn run(db: Arc<Db>, config: config::Webhook, last: Arc<LastScan>) {
+    let db = Extension(db);
+    let config = Arc::new(config);
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(30)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("failed to create webhook client: {:?}", e);
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+    loop {
+        interval.tick().await;
+
+        let snapshot = match Snapshot::scan(&db).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("webhook: failed to scan database: {:?}", e);
+                continue;
+            }
+        };
+
+        let (events, seq) = {
+            let mut last = last.0.lock().unwrap_or_else(|e| e.into_inner());
+            let events = last.0.as_ref().map(|last| last.diff(&snapshot)).unwrap_or_default();
+            last.0 = Some(snapshot);
+            if !events.is_empty() {
+                last.1 += 1;
+            }
+            (events, last.1)
+        };
+        if events.is_empty() {
+            continue;
+        }
+
+        let timestamp = time::OffsetDateTime::now_utc().unix_timestamp();
+        let id = format!("{timestamp}-{seq}");
+        let body = match serde_json::to_vec(&Delivery {
+            id: &id,
+            timestamp,
+            events: &events,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("webhook: failed to serialize events: {:?}", e);
+                continue;
+            }
+        };
+
+        info!("webhook: sending {} events as delivery {id}", events.len());
+        for url in &config.urls {
+            tokio::spawn(deliver(
+                client.clone(),
+                config.clone(),
+                url.clone(),
+                id.clone(),
+                body.clone(),
+            ));
+        }
+    }
+}